@@ -1,53 +1,576 @@
-use std::{collections::HashMap, fs::File, io::Write, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use cached_path::cached_path;
 use data_url::DataUrl;
+use futures::{Future, StreamExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use tempfile::TempDir;
 use tokio::task::spawn_blocking;
 
-use crate::Error;
+use crate::{
+    cache::AssetCacheConfig,
+    permission::{ReadDescriptor, UnaryPermission},
+    Error, Permissions,
+};
+
+/// Default limit of total bytes extracted from archive assets.
+pub const DEFAULT_EXTRACT_LIMIT: u64 = 1 << 30;
+
+/// Asset specification, either a source string (data URL, http(s) URL,
+/// `file://` URL or local path) or a detailed form.
+///
+/// Archive sources can also be written as e.g. `tar+https://...` to have
+/// them extracted. Git repositories are written as `git+https://...#rev` or
+/// `git+file://...#rev`, and checked out at the given revision (`HEAD` if
+/// omitted).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AssetSpec {
+    Source(String),
+    Detailed(AssetOptions),
+}
+
+/// Detailed asset specification, with either `url` or inline `content`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssetOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// File content written inline.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// How inline content is encoded.
+    #[serde(default, skip_serializing_if = "ContentEncoding::is_plain")]
+    pub encoding: ContentEncoding,
+    /// Extract `.tar`, `.tar.gz` or `.zip` archive into a directory.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub extract: bool,
+    /// Expected SHA-256 digest of the fetched file, in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Expected digest as a subresource integrity string, e.g.
+    /// `sha384-<base64>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+    /// Only fetch the pinned revision of git repository, without history.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shallow: bool,
+    /// Permission bits of the written files, e.g. `0755`. For directory
+    /// assets, only files inside are changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<FileMode>,
+    /// Numeric owner of the written files and directories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+}
+
+/// Numeric file owner, written as `uid` or `uid:gid`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for Owner {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::SpecError(format!("invalid owner: {}", s));
+        let (uid, gid) = match s.split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (s, None),
+        };
+        let uid = uid.trim().parse().map_err(|_| invalid())?;
+        let gid = gid
+            .map(|gid| gid.trim().parse().map_err(|_| invalid()))
+            .transpose()?;
+        Ok(Owner { uid, gid })
+    }
+}
+
+impl Serialize for Owner {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.gid {
+            Some(gid) => serializer.serialize_str(&format!("{}:{}", self.uid, gid)),
+            None => serializer.serialize_u32(self.uid),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Owner {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u32),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(uid) => Ok(Owner { uid, gid: None }),
+            Raw::Text(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    #[default]
+    Plain,
+    Base64,
+}
+
+impl ContentEncoding {
+    fn is_plain(&self) -> bool {
+        *self == ContentEncoding::Plain
+    }
+
+    fn decode(self, content: String) -> Result<Vec<u8>, Error> {
+        match self {
+            ContentEncoding::Plain => Ok(content.into_bytes()),
+            ContentEncoding::Base64 => {
+                // line breaks are common in long base64 content
+                let content = content.split_whitespace().collect::<String>();
+                base64::decode(content)
+                    .map_err(|e| Error::SpecError(format!("invalid base64 content: {}", e)))
+            }
+        }
+    }
+}
+
+/// Unix file mode, written as an octal string (`"0755"` or `0755` in YAML)
+/// or as a number (e.g. `0o755`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        u32::from_str_radix(digits, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(FileMode)
+            .ok_or_else(|| Error::SpecError(format!("invalid file mode: {}", s)))
+    }
+}
+
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u32),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(n) if n <= 0o7777 => Ok(FileMode(n)),
+            Raw::Number(n) => Err(de::Error::custom(format!("invalid file mode: {:o}", n))),
+            Raw::Text(s) => s.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+impl AssetSpec {
+    fn options(self) -> AssetOptions {
+        match self {
+            AssetSpec::Source(url) => match url.strip_prefix("tar+") {
+                Some(url) => AssetOptions {
+                    url: Some(url.into()),
+                    extract: true,
+                    ..Default::default()
+                },
+                None => AssetOptions {
+                    url: Some(url),
+                    ..Default::default()
+                },
+            },
+            AssetSpec::Detailed(options) => options,
+        }
+    }
+}
+
+/// Limits on fetching assets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssetLimits {
+    /// Maximum number of assets fetched at the same time.
+    pub parallelism: usize,
+    /// Maximum size in bytes of each asset, as fetched.
+    pub max_size: Option<u64>,
+    /// Maximum size in bytes of all assets prepared at once.
+    pub max_total_size: Option<u64>,
+    /// Maximum time for fetching each asset.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AssetLimits {
+    fn default() -> Self {
+        Self {
+            parallelism: 4,
+            max_size: None,
+            max_total_size: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Progress of preparing a single asset.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AssetProgress {
+    pub name: String,
+    pub state: AssetState,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetState {
+    Started,
+    /// Fetched with the given size in bytes.
+    Finished(u64),
+    Failed(String),
+}
+
+/// Where an asset is fetched from, with permissions already checked.
+enum Source {
+    Data(Vec<u8>),
+    Http(String),
+    Git {
+        url: String,
+        rev: String,
+        shallow: bool,
+    },
+    Local(PathBuf),
+}
+
+/// Size of a single asset counted against the limits while it is fetched.
+pub(crate) struct SizeCounter {
+    name: String,
+    size: u64,
+    max_size: Option<u64>,
+    /// Size of all assets prepared at once.
+    total: Arc<AtomicU64>,
+    max_total_size: Option<u64>,
+}
+
+impl SizeCounter {
+    /// Count size of asset `name` within `limits`, adding up to `total`.
+    pub fn new(name: &str, limits: &AssetLimits, total: Arc<AtomicU64>) -> Self {
+        Self {
+            name: name.into(),
+            size: 0,
+            max_size: limits.max_size,
+            total,
+            max_total_size: limits.max_total_size,
+        }
+    }
+
+    /// Size counted so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Check whether `n` more bytes would exceed the limits, without counting
+    /// them.
+    pub fn check(&self, n: u64) -> Result<(), Error> {
+        self.check_sizes(self.size + n, self.total.load(Ordering::SeqCst) + n)
+    }
+
+    /// Count `n` more bytes, failing once the limits are exceeded.
+    pub fn add(&mut self, n: u64) -> Result<(), Error> {
+        self.size += n;
+        let total = self.total.fetch_add(n, Ordering::SeqCst) + n;
+        self.check_sizes(self.size, total)
+    }
+
+    fn check_sizes(&self, size: u64, total: u64) -> Result<(), Error> {
+        if let Some(max) = self.max_size {
+            if size > max {
+                return Err(Error::AssetLimitError(format!(
+                    "{} has more than {} bytes",
+                    self.name, max
+                )));
+            }
+        }
+        if let Some(max) = self.max_total_size {
+            if total > max {
+                return Err(Error::AssetLimitError(format!(
+                    "assets have more than {} bytes in total",
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Job {
+    name: String,
+    source: Source,
+    extract: bool,
+    checksums: Vec<integrity::Checksum>,
+    mode: Option<FileMode>,
+    owner: Option<Owner>,
+}
 
 /// Managing assets needed for running task.
 pub struct AssetManager {
     tempdir: TempDir,
+    extract_limit: u64,
+    limits: AssetLimits,
+    cache: AssetCacheConfig,
 }
 
 impl AssetManager {
     pub fn new() -> Result<Self, Error> {
+        let tempdir = TempDir::new()?;
+        // make assets readable to the (possibly non-root) container user
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(tempdir.path(), std::fs::Permissions::from_mode(0o755))?;
+        }
         Ok(AssetManager {
-            tempdir: TempDir::new()?,
+            tempdir,
+            extract_limit: DEFAULT_EXTRACT_LIMIT,
+            limits: AssetLimits::default(),
+            cache: AssetCacheConfig::default(),
         })
     }
 
-    /// Parse or fetch all assets into temp directory
-    pub async fn prepare(&self, assets: HashMap<String, String>) -> Result<(), Error> {
-        for (k, v) in assets {
-            let file_path = self.tempdir.path().join(&k);
-            std::fs::create_dir_all(&file_path.parent().expect("should have parent"))?;
-            if v.starts_with("data:") {
-                let url = DataUrl::process(&v).map_err(|e| Error::SpecError(format!("{:?}", e)))?;
-                let (body, _) = url
-                    .decode_to_vec()
-                    .map_err(|e| Error::SpecError(format!("{:?}", e)))?;
+    /// Set limit of total bytes extracted from each archive.
+    pub fn set_extract_limit(&mut self, limit: u64) {
+        self.extract_limit = limit;
+    }
+
+    /// Set parallelism, size and time limits on fetching assets.
+    pub fn set_limits(&mut self, limits: AssetLimits) {
+        self.limits = limits;
+    }
+
+    /// Set where and how downloaded assets are cached between runs.
+    pub fn set_cache_config(&mut self, config: AssetCacheConfig) {
+        self.cache = config;
+    }
+
+    pub fn cache_config(&self) -> &AssetCacheConfig {
+        &self.cache
+    }
+
+    /// Parse, fetch or copy all assets into temp directory, concurrently.
+    /// Local files and directories (`file://` or plain paths) require read
+    /// permission. Progress of each asset is passed to `progress`.
+    pub async fn prepare<F, Fut>(
+        &self,
+        assets: HashMap<String, AssetSpec>,
+        permissions: &mut Permissions,
+        progress: F,
+    ) -> Result<(), Error>
+    where
+        F: Fn(AssetProgress) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        // check permissions first, as prompting cannot be done concurrently
+        let jobs = assets
+            .into_iter()
+            .map(|(name, spec)| resolve(name, spec, permissions))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let permissions = &*permissions;
+        let progress = &progress;
+        let total = Arc::new(AtomicU64::new(0));
+        let total = &total;
+        let mut results = futures::stream::iter(jobs)
+            .map(|job| async move {
+                let name = job.name.clone();
+                progress(AssetProgress {
+                    name: name.clone(),
+                    state: AssetState::Started,
+                })
+                .await;
+                let result = self.fetch(job, permissions, total).await;
+                progress(AssetProgress {
+                    name,
+                    state: match &result {
+                        Ok(size) => AssetState::Finished(*size),
+                        Err(e) => AssetState::Failed(e.to_string()),
+                    },
+                })
+                .await;
+                result
+            })
+            .buffer_unordered(self.limits.parallelism.max(1));
+        while let Some(result) = results.next().await {
+            result?;
+        }
+
+        if self.cache.max_size.is_some() {
+            let cache = self.cache.clone();
+            spawn_blocking(move || cache.prune())
+                .await
+                .map_err(|e| Error::UnknownError(format!("{:?}", e)))??;
+        }
+        Ok(())
+    }
+
+    /// Fetch a single asset within limits, returning its size as fetched.
+    async fn fetch(
+        &self,
+        job: Job,
+        permissions: &Permissions,
+        total: &Arc<AtomicU64>,
+    ) -> Result<u64, Error> {
+        let Job {
+            name,
+            source,
+            extract,
+            checksums,
+            mode,
+            owner,
+        } = job;
+        let file_path = self.tempdir.path().join(&name);
+        std::fs::create_dir_all(file_path.parent().expect("should have parent"))?;
+
+        let counter = SizeCounter::new(&name, &self.limits, total.clone());
+        let fetching = self.fetch_source(source, &file_path, counter);
+        let (fetched, counter) = match self.limits.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fetching)
+                .await
+                .map_err(|_| {
+                    Error::AssetLimitError(format!(
+                        "fetching {} timed out after {:?}",
+                        name, timeout
+                    ))
+                })??,
+            None => fetching.await?,
+        };
+
+        // hashing, extracting and copying are blocking, run them in a new
+        // thread
+        let extract_limit = self.extract_limit;
+        let read = permissions.read.clone();
+        spawn_blocking(move || {
+            for checksum in &checksums {
+                if let Err(e) = checksum.verify(&fetched, &name) {
+                    if fetched == file_path {
+                        // do not leave unverified content in assets
+                        std::fs::remove_file(&file_path)?;
+                    }
+                    return Err(e);
+                }
+            }
+
+            if extract {
+                log::debug!("extracting {:?} to: {:?}", fetched, file_path);
+                let archive = File::open(&fetched)?;
+                if fetched == file_path {
+                    // data URL has been written to where the directory goes
+                    std::fs::remove_file(&file_path)?;
+                }
+                archive::extract(archive, &file_path, extract_limit)?;
+            } else if fetched != file_path {
+                log::trace!("copying {:?} to: {:?}", fetched, file_path);
+                copy_local(&fetched, &file_path, &read)?;
+            }
+            if mode.is_some() || owner.is_some() {
+                set_attributes(&file_path, mode, owner)?;
+            }
+            Ok(counter.size())
+        })
+        .await
+        .map_err(|e| Error::UnknownError(format!("{:?}", e)))?
+    }
+
+    /// Get path of the fetched asset, which might be `file_path` itself, with
+    /// its size counted by `counter`.
+    async fn fetch_source(
+        &self,
+        source: Source,
+        file_path: &Path,
+        mut counter: SizeCounter,
+    ) -> Result<(PathBuf, SizeCounter), Error> {
+        match source {
+            Source::Data(body) => {
+                counter.add(body.len() as u64)?;
                 log::debug!("writing to: {:?}", file_path);
                 let mut file = File::create(file_path)?;
                 file.write_all(&body)?;
                 file.flush()?;
-            } else if v.starts_with("http") {
-                log::debug!("downloading: {}", k);
+                Ok((file_path.into(), counter))
+            }
+            Source::Git { url, rev, shallow } => {
+                log::debug!("checking out {}#{} to: {:?}", url, rev, file_path);
+                let cache = self.cache.clone();
+                let dst = file_path.to_path_buf();
+                let limit = self.extract_limit;
+                // dropping the task on timeout does not stop git, so give it
+                // the deadline as well
+                let deadline = self.limits.timeout.map(|t| Instant::now() + t);
+                // git commands are blocking, run them in a new thread
+                spawn_blocking(move || {
+                    cache.ensure_dir()?;
+                    let (repo, commit) = git::fetch(
+                        &cache.git_dir(),
+                        &url,
+                        &rev,
+                        shallow,
+                        cache.offline,
+                        deadline,
+                    )?;
+                    git::checkout(&repo, &commit, &dst, limit, deadline)?;
+                    counter.add(disk_size(&dst)?)?;
+                    Ok((dst, counter))
+                })
+                .await
+                .map_err(|e| Error::UnknownError(format!("{:?}", e)))?
+            }
+            Source::Http(url) => {
+                log::debug!("downloading: {}", url);
                 // do blocking reqwest in a new thread
-                let cached_path = spawn_blocking(move || cached_path(&v))
-                    .await
-                    .map_err(|e| Error::UnknownError(format!("{:?}", e)))??;
-                log::trace!("copying to: {:?}", file_path);
-                std::fs::copy(cached_path, file_path)?;
-            } else {
-                return Err(Error::SpecError(
-                    "asset must start with either data or http".into(),
-                ));
+                let cache = self.cache.clone();
+                let timeout = self.limits.timeout;
+                spawn_blocking(move || {
+                    let path = cache.fetch(&url, timeout, &mut counter)?;
+                    Ok((path, counter))
+                })
+                .await
+                .map_err(|e| Error::UnknownError(format!("{:?}", e)))?
             }
+            Source::Local(path) => spawn_blocking(move || {
+                // check size before copying anything
+                counter.add(disk_size(&path)?)?;
+                Ok((path, counter))
+            })
+            .await
+            .map_err(|e| Error::UnknownError(format!("{:?}", e)))?,
         }
-        Ok(())
+    }
+
+    /// Create a new manager with a copy of all assets prepared so far.
+    pub fn fork(&self) -> Result<Self, Error> {
+        let mut forked = Self::new()?;
+        forked.extract_limit = self.extract_limit;
+        forked.limits = self.limits.clone();
+        forked.cache = self.cache.clone();
+        copy_dir(self.path(), forked.path())?;
+        Ok(forked)
     }
 
     /// Get cache path
@@ -55,3 +578,1600 @@ impl AssetManager {
         self.tempdir.path()
     }
 }
+
+/// Parse asset specification, and check permissions for local sources.
+fn resolve(name: String, spec: AssetSpec, permissions: &mut Permissions) -> Result<Job, Error> {
+    let mut options = spec.options();
+    let checksums = integrity::Checksum::from_options(&options)?;
+    let source = match (options.url.take(), options.content.take()) {
+        (Some(url), None) => resolve_url(&name, url, &options, permissions)?,
+        (None, Some(content)) => Source::Data(options.encoding.decode(content)?),
+        _ => {
+            return Err(Error::SpecError(format!(
+                "asset {} should have either url or content",
+                name
+            )))
+        }
+    };
+    Ok(Job {
+        name,
+        source,
+        extract: options.extract,
+        checksums,
+        mode: options.mode,
+        owner: options.owner,
+    })
+}
+
+fn resolve_url(
+    name: &str,
+    v: String,
+    options: &AssetOptions,
+    permissions: &mut Permissions,
+) -> Result<Source, Error> {
+    let source = if v.starts_with("data:") {
+        let url = DataUrl::process(&v).map_err(|e| Error::SpecError(format!("{:?}", e)))?;
+        let (body, _) = url
+            .decode_to_vec()
+            .map_err(|e| Error::SpecError(format!("{:?}", e)))?;
+        Source::Data(body)
+    } else if let Some(url) = v.strip_prefix("git+") {
+        let (url, rev) = match url.split_once('#') {
+            Some((url, rev)) => (url, rev),
+            None => (url, "HEAD"),
+        };
+        // both are passed to git, which must not take them as options or
+        // refspecs
+        if rev.is_empty() || rev.starts_with('-') || rev.contains(':') {
+            return Err(Error::SpecError(format!(
+                "invalid revision for git asset {}: {}",
+                name, rev
+            )));
+        }
+        let url = if let Some(path) = url.strip_prefix("file://") {
+            permissions
+                .read_asset(Path::new(path))?
+                .to_string_lossy()
+                .into_owned()
+        } else if url.starts_with("https://") {
+            url.into()
+        } else {
+            return Err(Error::SpecError(format!(
+                "git asset {} should use https:// or file:// URL",
+                name
+            )));
+        };
+        if options.extract {
+            return Err(Error::SpecError(format!(
+                "git asset {} cannot be extracted",
+                name
+            )));
+        }
+        Source::Git {
+            url,
+            rev: rev.into(),
+            shallow: options.shallow,
+        }
+    } else if v.starts_with("http://") || v.starts_with("https://") {
+        Source::Http(v)
+    } else {
+        let path = PathBuf::from(v.strip_prefix("file://").unwrap_or(&v));
+        Source::Local(permissions.read_asset(&path)?)
+    };
+    Ok(source)
+}
+
+/// Set mode of files and owner of all entries under `path` recursively,
+/// without following symlinks.
+#[cfg(unix)]
+fn set_attributes(path: &Path, mode: Option<FileMode>, owner: Option<Owner>) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            set_attributes(&entry?.path(), mode, owner)?;
+        }
+    } else if metadata.file_type().is_symlink() {
+        // mode of symlinks is not used, and setting it changes the target
+    } else if let Some(FileMode(mode)) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(Owner { uid, gid }) = owner {
+        std::os::unix::fs::lchown(path, Some(uid), gid)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_attributes(_path: &Path, mode: Option<FileMode>, owner: Option<Owner>) -> Result<(), Error> {
+    if mode.is_some() || owner.is_some() {
+        log::warn!("file mode and owner of assets are only supported on unix");
+    }
+    Ok(())
+}
+
+/// Size of file or directory on disk, without following symlinks.
+fn disk_size(path: &Path) -> Result<u64, Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += disk_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Copy directory content recursively, keeping symlinks as they are, and
+/// mode and owner of every entry.
+fn copy_dir(src: &Path, dst: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+            let permissions = std::fs::symlink_metadata(entry.path())?.permissions();
+            std::fs::set_permissions(&target, permissions)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(entry.path())?, &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = std::fs::symlink_metadata(entry.path())?;
+            std::os::unix::fs::lchown(&target, Some(metadata.uid()), Some(metadata.gid()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy local file or directory, checking read permission for every symlink
+/// so that none of them leads out of the allowed paths. Copied files are
+/// readable by everyone, as the container user might not be the owner.
+fn copy_local(src: &Path, dst: &Path, read: &UnaryPermission<ReadDescriptor>) -> Result<(), Error> {
+    if !src.is_dir() {
+        std::fs::copy(src, dst)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let executable = std::fs::metadata(dst)?.permissions().mode() & 0o111 != 0;
+            let mode = if executable { 0o755 } else { 0o644 };
+            std::fs::set_permissions(dst, std::fs::Permissions::from_mode(mode))?;
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let path = if entry.file_type()?.is_symlink() {
+            read.check_resolved(&entry.path())?
+        } else {
+            entry.path()
+        };
+        copy_local(&path, &dst.join(entry.file_name()), read)?;
+    }
+    Ok(())
+}
+
+mod archive {
+    use std::{
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+        path::{Component, Path, PathBuf},
+    };
+
+    use flate2::read::GzDecoder;
+
+    use crate::{permission::normalize_path, Error};
+
+    /// Extract tar, gzipped tar or zip archive (detected by content) into
+    /// `dst`. Entries escaping `dst` and links are rejected, and extraction
+    /// fails once more than `limit` bytes are written.
+    pub fn extract(mut archive: File, dst: &Path, limit: u64) -> Result<(), Error> {
+        let mut magic = [0u8; 4];
+        let n = archive.read(&mut magic)?;
+        archive.seek(SeekFrom::Start(0))?;
+        std::fs::create_dir_all(dst)?;
+        let mut remaining = limit;
+        match &magic[..n] {
+            [0x1f, 0x8b, ..] => extract_tar(GzDecoder::new(archive), dst, &mut remaining, false),
+            [b'P', b'K', 3, 4] => extract_zip(archive, dst, &mut remaining),
+            _ => extract_tar(archive, dst, &mut remaining, false),
+        }
+    }
+
+    /// Extract tar archive into `dst`. Symlinks are only created if
+    /// `symlinks` is set and they point inside `dst`. They are never
+    /// followed when writing other entries.
+    pub fn extract_tar(
+        reader: impl Read,
+        dst: &Path,
+        remaining: &mut u64,
+        symlinks: bool,
+    ) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let kind = entry.header().entry_type();
+            if kind.is_pax_global_extensions() || kind.is_pax_local_extensions() {
+                // metadata only, e.g. commit ID written by `git archive`
+                continue;
+            } else if kind.is_dir() {
+                std::fs::create_dir_all(target(dst, &path)?)?;
+            } else if kind.is_file() {
+                let mode = entry.header().mode().ok();
+                write_file(&mut entry, &target(dst, &path)?, mode, remaining)?;
+            } else if kind.is_symlink() && symlinks {
+                let link = entry.link_name()?.ok_or_else(|| {
+                    Error::ArchiveError(format!("missing link target for {}", path.display()))
+                })?;
+                write_symlink(&link, &target(dst, &path)?, &path)?;
+            } else {
+                return Err(Error::ArchiveError(format!(
+                    "unsupported entry type for {}",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_zip(reader: File, dst: &Path, remaining: &mut u64) -> Result<(), Error> {
+        let mut archive =
+            zip::ZipArchive::new(reader).map_err(|e| Error::ArchiveError(e.to_string()))?;
+        for i in 0..archive.len() {
+            let mut file = archive
+                .by_index(i)
+                .map_err(|e| Error::ArchiveError(e.to_string()))?;
+            let path = PathBuf::from(file.name());
+            if file
+                .unix_mode()
+                .is_some_and(|mode| mode & 0o170000 == 0o120000)
+            {
+                return Err(Error::ArchiveError(format!(
+                    "unsupported entry type for {}",
+                    path.display()
+                )));
+            } else if file.is_dir() {
+                std::fs::create_dir_all(target(dst, &path)?)?;
+            } else {
+                let mode = file.unix_mode();
+                write_file(&mut file, &target(dst, &path)?, mode, remaining)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get path of an entry inside `dst`, rejecting the ones escaping it,
+    /// also through symlinks extracted before.
+    fn target(dst: &Path, path: &Path) -> Result<PathBuf, Error> {
+        let outside = || {
+            Error::ArchiveError(format!(
+                "entry {} is outside of extraction directory",
+                path.display()
+            ))
+        };
+        let mut target = dst.to_path_buf();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => target.push(name),
+                Component::CurDir => continue,
+                _ => return Err(outside()),
+            }
+            if std::fs::symlink_metadata(&target).is_ok_and(|m| m.file_type().is_symlink()) {
+                return Err(outside());
+            }
+        }
+        Ok(target)
+    }
+
+    /// Create symlink at `target` for entry `path`, if `link` is relative and
+    /// stays inside the extraction directory.
+    fn write_symlink(link: &Path, target: &Path, path: &Path) -> Result<(), Error> {
+        let resolved = normalize_path(&path.parent().unwrap_or(path).join(link));
+        if link.is_absolute() || matches!(resolved.components().next(), Some(Component::ParentDir))
+        {
+            return Err(Error::ArchiveError(format!(
+                "link {} -> {} is outside of extraction directory",
+                path.display(),
+                link.display()
+            )));
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        #[cfg(unix)]
+        let created = std::os::unix::fs::symlink(link, target).map_err(Error::from);
+        #[cfg(not(unix))]
+        let created = Err(Error::ArchiveError(format!(
+            "unsupported entry type for {}",
+            path.display()
+        )));
+        created
+    }
+
+    /// Write an entry with its permission bits, e.g. for executable scripts,
+    /// but never setuid or setgid.
+    fn write_file(
+        reader: &mut impl Read,
+        path: &Path,
+        mode: Option<u32>,
+        remaining: &mut u64,
+    ) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(path)?;
+        // read one more byte to tell whether the limit is exceeded
+        let written = io::copy(&mut reader.take(*remaining + 1), &mut file)?;
+        if written > *remaining {
+            return Err(Error::ArchiveError(
+                "extracted size exceeds the limit".into(),
+            ));
+        }
+        *remaining -= written;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::Write;
+
+        use tar::{EntryType, Header};
+        use tempfile::TempDir;
+
+        use super::*;
+
+        /// Tar entry with the name written as is, as `tar` refuses to write
+        /// names with `..` or a root.
+        fn tar_entry(name: &str, kind: EntryType, data: &[u8]) -> (Header, Vec<u8>) {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            (header, data.to_vec())
+        }
+
+        fn tar_archive(entries: Vec<(Header, Vec<u8>)>) -> Vec<u8> {
+            let mut builder = tar::Builder::new(vec![]);
+            for (mut header, data) in entries {
+                header.set_cksum();
+                builder.append(&header, &data[..]).unwrap();
+            }
+            builder.into_inner().unwrap()
+        }
+
+        fn zip_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+            let mut writer = zip::ZipWriter::new(io::Cursor::new(vec![]));
+            for (name, data) in entries {
+                writer
+                    .start_file(*name, zip::write::FileOptions::default())
+                    .unwrap();
+                writer.write_all(data).unwrap();
+            }
+            writer.finish().unwrap().into_inner()
+        }
+
+        fn extract_bytes(bytes: &[u8], limit: u64) -> (TempDir, Result<(), Error>) {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("archive");
+            std::fs::write(&path, bytes).unwrap();
+            let result = extract(File::open(&path).unwrap(), &dir.path().join("out"), limit);
+            (dir, result)
+        }
+
+        fn assert_rejected(bytes: &[u8], message: &str) {
+            let (dir, result) = extract_bytes(bytes, 1 << 20);
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains(message), "unexpected error: {}", err);
+            // nothing is written next to the extraction directory
+            let names = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name != "archive" && name != "out")
+                .collect::<Vec<_>>();
+            assert!(names.is_empty(), "escaped entries: {:?}", names);
+        }
+
+        #[test]
+        fn extract_tar_and_zip() {
+            let tar = tar_archive(vec![
+                tar_entry("dir/", EntryType::Directory, b""),
+                tar_entry("dir/a.txt", EntryType::Regular, b"hello"),
+                tar_entry("./b.txt", EntryType::Regular, b"world"),
+            ]);
+            let (dir, result) = extract_bytes(&tar, 10);
+            result.unwrap();
+            let out = dir.path().join("out");
+            assert_eq!(std::fs::read(out.join("dir/a.txt")).unwrap(), b"hello");
+            assert_eq!(std::fs::read(out.join("b.txt")).unwrap(), b"world");
+
+            let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+            gz.write_all(&tar).unwrap();
+            let (dir, result) = extract_bytes(&gz.finish().unwrap(), 10);
+            result.unwrap();
+            assert!(dir.path().join("out/dir/a.txt").exists());
+
+            let zip = zip_archive(&[("dir/a.txt", b"hello"), ("b.txt", b"world")]);
+            let (dir, result) = extract_bytes(&zip, 10);
+            result.unwrap();
+            assert_eq!(
+                std::fs::read(dir.path().join("out/dir/a.txt")).unwrap(),
+                b"hello"
+            );
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn keep_permission_bits() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode =
+                |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+            let (mut script, data) = tar_entry("run.sh", EntryType::Regular, b"echo");
+            script.set_mode(0o6755);
+            let tar = tar_archive(vec![
+                (script, data),
+                tar_entry("data.txt", EntryType::Regular, b"data"),
+            ]);
+            let (dir, result) = extract_bytes(&tar, 10);
+            result.unwrap();
+            assert_eq!(mode(dir.path().join("out/run.sh")), 0o755);
+            assert_eq!(mode(dir.path().join("out/data.txt")), 0o644);
+
+            let mut writer = zip::ZipWriter::new(io::Cursor::new(vec![]));
+            let options = zip::write::FileOptions::default();
+            writer
+                .start_file("run.sh", options.unix_permissions(0o4755))
+                .unwrap();
+            writer.write_all(b"echo").unwrap();
+            writer
+                .start_file("data.txt", options.unix_permissions(0o600))
+                .unwrap();
+            writer.write_all(b"data").unwrap();
+            let zip = writer.finish().unwrap().into_inner();
+            let (dir, result) = extract_bytes(&zip, 10);
+            result.unwrap();
+            assert_eq!(mode(dir.path().join("out/run.sh")), 0o755);
+            assert_eq!(mode(dir.path().join("out/data.txt")), 0o600);
+        }
+
+        #[test]
+        fn reject_tar_traversal() {
+            for name in ["../evil.txt", "dir/../../evil.txt", "/tmp/evil.txt"] {
+                let tar = tar_archive(vec![tar_entry(name, EntryType::Regular, b"evil")]);
+                assert_rejected(&tar, "outside of extraction directory");
+            }
+        }
+
+        #[test]
+        fn reject_tar_links() {
+            for kind in [EntryType::Symlink, EntryType::Link] {
+                let (mut header, data) = tar_entry("link", kind, b"");
+                header.set_link_name("../../etc/passwd").unwrap();
+                // a file written through the link afterwards must not escape
+                let tar = tar_archive(vec![
+                    (header, data),
+                    tar_entry("link", EntryType::Regular, b"evil"),
+                ]);
+                assert_rejected(&tar, "unsupported entry type for link");
+            }
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn extract_inner_symlinks() {
+            let extract_with_links = |entries| {
+                let dir = tempfile::tempdir().unwrap();
+                let tar = tar_archive(entries);
+                let result = extract_tar(&tar[..], &dir.path().join("out"), &mut 1024, true);
+                (dir, result)
+            };
+            let link = |name: &str, target: &str| {
+                let (mut header, data) = tar_entry(name, EntryType::Symlink, b"");
+                header.set_link_name(target).unwrap();
+                (header, data)
+            };
+
+            let (dir, result) = extract_with_links(vec![
+                tar_entry("a.txt", EntryType::Regular, b"hello"),
+                link("dir/link", "../a.txt"),
+            ]);
+            result.unwrap();
+            let out = dir.path().join("out");
+            assert_eq!(
+                std::fs::read_link(out.join("dir/link")).unwrap(),
+                Path::new("../a.txt")
+            );
+            assert_eq!(std::fs::read(out.join("dir/link")).unwrap(), b"hello");
+
+            for target in ["../a.txt", "dir/../../a.txt", "/etc/passwd"] {
+                let (_dir, result) = extract_with_links(vec![link("link", target)]);
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("outside of extraction directory"), "{}", err);
+            }
+
+            // entries are never written through symlinks
+            let (dir, result) = extract_with_links(vec![
+                tar_entry("sub/", EntryType::Directory, b""),
+                link("dir", "sub"),
+                tar_entry("dir/a.txt", EntryType::Regular, b"hello"),
+            ]);
+            let err = result.unwrap_err().to_string();
+            assert!(err.contains("outside of extraction directory"), "{}", err);
+            assert!(!dir.path().join("out/sub/a.txt").exists());
+        }
+
+        #[test]
+        fn reject_zip_traversal() {
+            for name in ["../evil.txt", "dir/../../evil.txt", "/tmp/evil.txt"] {
+                let zip = zip_archive(&[(name, b"evil")]);
+                assert_rejected(&zip, "outside of extraction directory");
+            }
+        }
+
+        #[test]
+        fn reject_zip_symlinks() {
+            let mut zip = zip_archive(&[("link", b"../../etc/passwd")]);
+            // mark the entry as symlink in external attributes of the central
+            // directory header, which `zip` cannot write
+            let header = zip
+                .windows(4)
+                .position(|w| w == [b'P', b'K', 1, 2])
+                .unwrap();
+            let attributes = (0o120777u32 << 16).to_le_bytes();
+            zip[header + 38..header + 42].copy_from_slice(&attributes);
+            assert_rejected(&zip, "unsupported entry type for link");
+        }
+
+        #[test]
+        fn limit_extracted_size() {
+            let tar = tar_archive(vec![
+                tar_entry("a.txt", EntryType::Regular, b"hello"),
+                tar_entry("b.txt", EntryType::Regular, b"world"),
+            ]);
+            assert!(extract_bytes(&tar, 10).1.is_ok());
+            let err = extract_bytes(&tar, 9).1.unwrap_err().to_string();
+            assert!(err.contains("extracted size exceeds the limit"));
+
+            let zip = zip_archive(&[("a.txt", b"hello"), ("b.txt", b"world")]);
+            assert!(extract_bytes(&zip, 10).1.is_ok());
+            let err = extract_bytes(&zip, 9).1.unwrap_err().to_string();
+            assert!(err.contains("extracted size exceeds the limit"));
+
+            // highly compressed entries are limited by their extracted size
+            let zip = zip_archive(&[("zeros", &[0u8; 1 << 16])]);
+            assert!(zip.len() < 1 << 10);
+            let err = extract_bytes(&zip, 1 << 10).1.unwrap_err().to_string();
+            assert!(err.contains("extracted size exceeds the limit"));
+        }
+    }
+}
+
+mod integrity {
+    use std::{fs::File, io, path::Path};
+
+    use sha2::{Digest, Sha256, Sha384, Sha512};
+
+    use super::AssetOptions;
+    use crate::Error;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Algorithm {
+        Sha256,
+        Sha384,
+        Sha512,
+    }
+
+    impl Algorithm {
+        fn name(self) -> &'static str {
+            match self {
+                Algorithm::Sha256 => "sha256",
+                Algorithm::Sha384 => "sha384",
+                Algorithm::Sha512 => "sha512",
+            }
+        }
+    }
+
+    /// Expected digests of an asset file, any of which should match.
+    #[derive(Debug, PartialEq)]
+    pub struct Checksum {
+        digests: Vec<(Algorithm, Vec<u8>)>,
+        /// Written as SRI string rather than hex.
+        sri: bool,
+    }
+
+    impl Checksum {
+        pub fn from_options(options: &AssetOptions) -> Result<Vec<Self>, Error> {
+            let mut checksums = vec![];
+            if let Some(sha256) = &options.sha256 {
+                let digest = hex::decode(sha256.trim())
+                    .map_err(|e| Error::SpecError(format!("invalid sha256 {}: {}", sha256, e)))?;
+                checksums.push(Checksum {
+                    digests: vec![(Algorithm::Sha256, digest)],
+                    sri: false,
+                });
+            }
+            if let Some(integrity) = &options.integrity {
+                checksums.push(Self::from_sri(integrity)?);
+            }
+            Ok(checksums)
+        }
+
+        /// Parse SRI string with one or more space-separated hashes. Hashes
+        /// of unsupported algorithms are ignored, as required by the spec.
+        fn from_sri(s: &str) -> Result<Self, Error> {
+            let invalid = || Error::SpecError(format!("invalid integrity string: {}", s));
+            let mut digests = vec![];
+            for hash in s.split_whitespace() {
+                let (algorithm, digest) = hash.split_once('-').ok_or_else(invalid)?;
+                let algorithm = match algorithm {
+                    "sha256" => Algorithm::Sha256,
+                    "sha384" => Algorithm::Sha384,
+                    "sha512" => Algorithm::Sha512,
+                    _ => continue,
+                };
+                // ignore options after `?`, as allowed by the SRI spec
+                let digest = digest.split('?').next().unwrap_or_default();
+                let digest = base64::decode(digest).map_err(|_| invalid())?;
+                digests.push((algorithm, digest));
+            }
+            if digests.is_empty() {
+                return Err(invalid());
+            }
+            Ok(Checksum { digests, sri: true })
+        }
+
+        /// Check digest of the file at `path`, which is fetched for asset
+        /// `name`.
+        pub fn verify(&self, path: &Path, name: &str) -> Result<(), Error> {
+            if path.is_dir() {
+                return Err(Error::SpecError(format!(
+                    "cannot check integrity of directory asset {}",
+                    name
+                )));
+            }
+            let mut actual: Vec<(Algorithm, Vec<u8>)> = vec![];
+            for (algorithm, digest) in &self.digests {
+                let hashed = match actual.iter().find(|(a, _)| a == algorithm) {
+                    Some((_, hashed)) => hashed,
+                    None => {
+                        let mut file = File::open(path)?;
+                        let hashed = match algorithm {
+                            Algorithm::Sha256 => hash::<Sha256>(&mut file)?,
+                            Algorithm::Sha384 => hash::<Sha384>(&mut file)?,
+                            Algorithm::Sha512 => hash::<Sha512>(&mut file)?,
+                        };
+                        actual.push((*algorithm, hashed));
+                        &actual.last().expect("just pushed").1
+                    }
+                };
+                if hashed == digest {
+                    return Ok(());
+                }
+            }
+            Err(Error::IntegrityError {
+                asset: name.into(),
+                expected: self.format(&self.digests),
+                actual: self.format(&actual),
+            })
+        }
+
+        fn format(&self, digests: &[(Algorithm, Vec<u8>)]) -> String {
+            digests
+                .iter()
+                .map(|(algorithm, digest)| {
+                    if self.sri {
+                        format!("{}-{}", algorithm.name(), base64::encode(digest))
+                    } else {
+                        hex::encode(digest)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+
+    fn hash<D: Digest + io::Write>(file: &mut File) -> Result<Vec<u8>, Error> {
+        let mut hasher = D::new();
+        io::copy(file, &mut hasher)?;
+        Ok(hasher.finalize().to_vec())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // digests of `hello`
+        const SHA256_HEX: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        const SHA256_SRI: &str = "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        const SHA512_SRI: &str = "sha512-m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==";
+
+        fn checksums(
+            sha256: Option<&str>,
+            integrity: Option<&str>,
+        ) -> Result<Vec<Checksum>, Error> {
+            Checksum::from_options(&AssetOptions {
+                sha256: sha256.map(Into::into),
+                integrity: integrity.map(Into::into),
+                ..Default::default()
+            })
+        }
+
+        fn verify(checksums: &[Checksum]) -> Result<(), Error> {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("hello");
+            std::fs::write(&path, "hello").unwrap();
+            checksums.iter().try_for_each(|c| c.verify(&path, "hello"))
+        }
+
+        #[test]
+        fn parse_checksums() {
+            let parsed = checksums(Some(SHA256_HEX), Some(SHA256_SRI)).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[0].digests[0].0, Algorithm::Sha256);
+            assert!(!parsed[0].sri);
+            assert_eq!(parsed[0].digests[0].1, parsed[1].digests[0].1);
+
+            // options after `?` and unknown algorithms are ignored
+            let integrity = format!("md5-AAAA {}?opt {}", SHA256_SRI, SHA512_SRI);
+            let parsed = checksums(None, Some(&integrity)).unwrap();
+            let algorithms = parsed[0]
+                .digests
+                .iter()
+                .map(|(a, _)| *a)
+                .collect::<Vec<_>>();
+            assert_eq!(algorithms, vec![Algorithm::Sha256, Algorithm::Sha512]);
+
+            assert!(checksums(Some("not hex"), None).is_err());
+            assert!(checksums(None, Some("sha256-not base64!")).is_err());
+            assert!(checksums(None, Some("sha256")).is_err());
+            assert!(checksums(None, Some("md5-AAAA")).is_err());
+            assert!(checksums(None, Some("")).is_err());
+        }
+
+        #[test]
+        fn verify_checksums() {
+            verify(&checksums(Some(SHA256_HEX), Some(SHA512_SRI)).unwrap()).unwrap();
+
+            // any supported hash matching is enough
+            let wrong = format!("sha256-{}", base64::encode([0u8; 32]));
+            let integrity = format!("{} {}", wrong, SHA512_SRI);
+            verify(&checksums(None, Some(&integrity)).unwrap()).unwrap();
+
+            match verify(&checksums(None, Some(&wrong)).unwrap()) {
+                Err(Error::IntegrityError {
+                    asset,
+                    expected,
+                    actual,
+                }) => {
+                    assert_eq!(asset, "hello");
+                    assert_eq!(expected, wrong);
+                    assert_eq!(actual, SHA256_SRI);
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+
+            let wrong = hex::encode([0u8; 32]);
+            match verify(&checksums(Some(&wrong), None).unwrap()) {
+                Err(Error::IntegrityError {
+                    expected, actual, ..
+                }) => {
+                    assert_eq!(expected, wrong);
+                    assert_eq!(actual, SHA256_HEX);
+                }
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+}
+
+pub(crate) mod git {
+    use std::{
+        fs::File,
+        io::Read,
+        path::{Path, PathBuf},
+        process::{Child, Command, Stdio},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread::JoinHandle,
+        time::{Duration, Instant},
+    };
+
+    use fs2::FileExt;
+    use sha2::{Digest, Sha256};
+
+    use super::archive;
+    use crate::Error;
+
+    /// File touched in the repository whenever it is used.
+    pub const USED_MARKER: &str = "srun-used";
+
+    /// Protocols git may use, also when following redirects.
+    const ALLOWED_PROTOCOLS: &str = "https:file";
+
+    /// How often to retry locking a repository fetched by someone else.
+    const LOCK_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Fetch `rev` of repository at `url` into a bare repository cached under
+    /// `cache`, returning the repository path and the resolved commit. In
+    /// offline mode, `rev` is resolved from what has been fetched before.
+    /// Fetching is killed once `deadline` passes.
+    pub fn fetch(
+        cache: &Path,
+        url: &str,
+        rev: &str,
+        shallow: bool,
+        offline: bool,
+        deadline: Option<Instant>,
+    ) -> Result<(PathBuf, String), Error> {
+        if url.starts_with('-') || rev.starts_with('-') {
+            return Err(Error::GitError(format!(
+                "invalid repository {}#{}",
+                url, rev
+            )));
+        }
+        let key = hex::encode(Sha256::digest(url.as_bytes()));
+        let repo = cache.join(&key);
+
+        // serialize fetches into the same repository, also across processes
+        std::fs::create_dir_all(cache)?;
+        let lock = File::create(cache.join(format!("{}.lock", key)))?;
+        while lock.try_lock_exclusive().is_err() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(timed_out(&["fetch", url]));
+            }
+            std::thread::sleep(LOCK_INTERVAL);
+        }
+
+        if !repo.exists() {
+            std::fs::create_dir_all(&repo)?;
+            run(Command::new("git")
+                .args(["init", "--bare", "-q"])
+                .arg(&repo))?;
+            git(&repo, &["config", "srun.url", url])?;
+        }
+        // mark as recently used for cache pruning
+        File::create(repo.join(USED_MARKER))?;
+
+        // pinned commit fetched before, no need to touch the remote
+        let is_commit = rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit());
+        if is_commit && git(&repo, &["cat-file", "-e", &format!("{}^{{commit}}", rev)]).is_ok() {
+            log::debug!("using cached commit {} of {}", rev, url);
+            return Ok((repo, rev.into()));
+        }
+
+        // remember where `rev` pointed to, so that it can be used offline
+        let cached_ref = format!("refs/srun/{}", hex::encode(rev));
+        if offline {
+            let commit = git(&repo, &["rev-parse", "--verify", "-q", &cached_ref])
+                .map_err(|_| Error::GitError(format!("{}#{} is not cached", url, rev)))?;
+            return Ok((repo, commit));
+        }
+
+        let mut args = vec!["fetch", "-q", "--no-tags"];
+        if shallow {
+            args.push("--depth=1");
+        }
+        args.extend(&["--", url, rev]);
+        run_until(
+            Command::new("git").arg("--git-dir").arg(&repo).args(&args),
+            deadline,
+        )?;
+        let commit = git(&repo, &["rev-parse", "FETCH_HEAD^{commit}"])?;
+        git(&repo, &["update-ref", &cached_ref, &commit])?;
+        Ok((repo, commit))
+    }
+
+    /// Write files of `commit` into `dst`, with the same checks and size
+    /// limit as archive assets. Symlinks are kept if they point inside
+    /// `dst`, and files are written as committed, ignoring `export-ignore` and
+    /// `export-subst` attributes of the repository. Checking out is stopped
+    /// once `deadline` passes.
+    pub fn checkout(
+        repo: &Path,
+        commit: &str,
+        dst: &Path,
+        limit: u64,
+        deadline: Option<Instant>,
+    ) -> Result<(), Error> {
+        // attributes here take precedence over the ones in the archived tree
+        std::fs::create_dir_all(repo.join("info"))?;
+        std::fs::write(
+            repo.join("info/attributes"),
+            "* -export-ignore -export-subst\n",
+        )?;
+        let mut command = Command::new("git");
+        command
+            .arg("--git-dir")
+            .arg(repo)
+            // files are not writable by others, as with a usual umask
+            .args(["-c", "tar.umask=0022", "archive", "--format=tar", commit]);
+        let mut child = spawn(&mut command)?;
+        let watchdog = Watchdog::start(&child, deadline);
+        let stdout = child.stdout.take().expect("stdout should be piped");
+        let stderr = read_in_background(child.stderr.take());
+        std::fs::create_dir_all(dst)?;
+        let mut remaining = limit;
+        let extracted = archive::extract_tar(stdout, dst, &mut remaining, true);
+        if extracted.is_err() {
+            // git might be blocked writing to the pipe
+            let _ = child.kill();
+        }
+        let status = child.wait()?;
+        if watchdog.finish() {
+            return Err(timed_out(&["archive", commit]));
+        }
+        // git fails with broken pipe when extraction stops early
+        extracted?;
+        if !status.success() {
+            return Err(Error::GitError(collect(stderr).trim().into()));
+        }
+        Ok(())
+    }
+
+    pub fn git(repo: &Path, args: &[&str]) -> Result<String, Error> {
+        run(Command::new("git").arg("--git-dir").arg(repo).args(args))
+    }
+
+    pub(super) fn run(command: &mut Command) -> Result<String, Error> {
+        run_until(command, None)
+    }
+
+    /// Run git command, killing it with all processes it starts once
+    /// `deadline` passes.
+    fn run_until(command: &mut Command, deadline: Option<Instant>) -> Result<String, Error> {
+        log::trace!("running: {:?}", command);
+        let mut child = spawn(command)?;
+        let watchdog = Watchdog::start(&child, deadline);
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        let status = child.wait()?;
+        if watchdog.finish() {
+            return Err(timed_out(&[&format!("{:?}", command)]));
+        }
+        if status.success() {
+            Ok(collect(stdout).trim().into())
+        } else {
+            Err(Error::GitError(collect(stderr).trim().into()))
+        }
+    }
+
+    fn spawn(command: &mut Command) -> Result<Child, Error> {
+        command
+            .env("GIT_ALLOW_PROTOCOL", ALLOWED_PROTOCOLS)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // in a new process group, so that helpers such as remote-https can be
+        // killed together
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+        Ok(command.spawn()?)
+    }
+
+    fn timed_out(what: &[&str]) -> Error {
+        Error::AssetLimitError(format!("git {} timed out", what.join(" ")))
+    }
+
+    fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+        std::thread::spawn(move || {
+            let mut output = vec![];
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut output);
+            }
+            String::from_utf8_lossy(&output).into_owned()
+        })
+    }
+
+    fn collect(output: JoinHandle<String>) -> String {
+        output.join().unwrap_or_default()
+    }
+
+    /// Thread killing a git process group once the deadline passes.
+    struct Watchdog {
+        /// Dropped to stop watching.
+        stop: Option<mpsc::Sender<()>>,
+        fired: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Watchdog {
+        fn start(child: &Child, deadline: Option<Instant>) -> Self {
+            let fired = Arc::new(AtomicBool::new(false));
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => {
+                    return Self {
+                        stop: None,
+                        fired,
+                        thread: None,
+                    }
+                }
+            };
+            let (stop, stopped) = mpsc::channel::<()>();
+            let pid = child.id();
+            let thread = {
+                let fired = fired.clone();
+                std::thread::spawn(move || {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(timeout) {
+                        log::debug!("killing git process group {}", pid);
+                        fired.store(true, Ordering::SeqCst);
+                        kill_group(pid);
+                    }
+                })
+            };
+            Self {
+                stop: Some(stop),
+                fired,
+                thread: Some(thread),
+            }
+        }
+
+        /// Stop watching, returning whether the process has been killed.
+        fn finish(mut self) -> bool {
+            drop(self.stop.take());
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+            self.fired.load(Ordering::SeqCst)
+        }
+    }
+
+    #[cfg(unix)]
+    fn kill_group(pid: u32) {
+        // SAFETY: only sends a signal to the process group started for git
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn kill_group(pid: u32) {
+        log::warn!("cannot stop git process {} on this platform", pid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(content: &str, sha256: &str) -> AssetSpec {
+        AssetSpec::Detailed(AssetOptions {
+            content: Some(content.into()),
+            sha256: Some(sha256.into()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn reject_unsafe_git_urls() {
+        let mut permissions = Permissions::default();
+        for url in [
+            "git+--upload-pack=touch /tmp/pwned#x",
+            "git+https://example.com/repo.git#--upload-pack=x",
+            "git+https://example.com/repo.git#refs/*:refs/*",
+            "git+ssh://git@example.com/repo.git#main",
+            "git+/tmp/repo#main",
+            "git+ext::sh -c touch% /tmp/pwned#main",
+        ] {
+            let result = resolve(
+                "repo".into(),
+                AssetSpec::Source(url.into()),
+                &mut permissions,
+            );
+            assert!(
+                matches!(result, Err(Error::SpecError(_))),
+                "{} should be rejected",
+                url
+            );
+        }
+        assert!(permissions.take_audit().is_empty());
+
+        // local repositories need read permission
+        let mut permissions = Permissions::from_options(&crate::PermissionsOptions {
+            allow_read: Some(vec!["/allowed".into()]),
+            ..Default::default()
+        });
+        let result = resolve(
+            "repo".into(),
+            AssetSpec::Source("git+file:///tmp/repo#main".into()),
+            &mut permissions,
+        );
+        assert!(matches!(result, Err(Error::PermissionDeniedError(_))));
+    }
+
+    #[test]
+    fn fetch_git_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        let work = |args: &[&str]| {
+            git::run(
+                std::process::Command::new("git")
+                    .arg("-C")
+                    .arg(&origin)
+                    .args(["-c", "user.name=srun", "-c", "user.email=srun@localhost"])
+                    .args(args),
+            )
+            .unwrap()
+        };
+        std::fs::create_dir_all(&origin).unwrap();
+        work(&["init", "-q"]);
+        std::fs::write(origin.join("a.txt"), "hello").unwrap();
+        work(&["add", "a.txt"]);
+        work(&["commit", "-q", "-m", "init"]);
+        let head = work(&["rev-parse", "HEAD"]);
+
+        let cache = dir.path().join("cache");
+        let url = origin.to_str().unwrap().to_string();
+        let threads = (0..4)
+            .map(|_| {
+                let (cache, url) = (cache.clone(), url.clone());
+                std::thread::spawn(move || git::fetch(&cache, &url, "HEAD", false, false, None))
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            let (_, commit) = thread.join().unwrap().unwrap();
+            assert_eq!(commit, head);
+        }
+
+        assert!(git::fetch(&cache, "--upload-pack=x", "HEAD", false, false, None).is_err());
+        assert!(git::fetch(&cache, &url, "--upload-pack=x", false, false, None).is_err());
+    }
+
+    #[test]
+    fn kill_git_after_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        // accept connections but never answer
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/repo", listener.local_addr().unwrap());
+        std::thread::spawn(move || listener.incoming().collect::<Vec<_>>());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..2 {
+            let (cache, url, tx) = (cache.clone(), url.clone(), tx.clone());
+            std::thread::spawn(move || {
+                let deadline = Instant::now() + Duration::from_millis(500);
+                tx.send(git::fetch(
+                    &cache,
+                    &url,
+                    "HEAD",
+                    false,
+                    false,
+                    Some(deadline),
+                ))
+                .unwrap();
+            });
+        }
+        // the lock is released and waiting for it is bounded as well
+        for _ in 0..2 {
+            let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(matches!(result, Err(Error::AssetLimitError(_))));
+        }
+    }
+
+    #[test]
+    fn count_sizes() {
+        let total = Arc::new(AtomicU64::new(0));
+        let counter = |max_size, max_total_size| {
+            let limits = AssetLimits {
+                max_size,
+                max_total_size,
+                ..Default::default()
+            };
+            SizeCounter::new("asset", &limits, total.clone())
+        };
+
+        let mut a = counter(Some(10), Some(15));
+        a.check(10).unwrap();
+        assert!(matches!(a.check(11), Err(Error::AssetLimitError(_))));
+        a.add(6).unwrap();
+        assert!(a.add(5).is_err());
+
+        // the total is shared between assets
+        let mut b = counter(None, Some(15));
+        assert!(b.check(5).is_err());
+        b.add(4).unwrap();
+        assert_eq!(total.load(Ordering::SeqCst), 15);
+        assert!(b.add(1).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checkout_git_as_committed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        let work = |args: &[&str]| {
+            git::run(
+                std::process::Command::new("git")
+                    .arg("-C")
+                    .arg(&origin)
+                    .args(["-c", "user.name=srun", "-c", "user.email=srun@localhost"])
+                    .args(args),
+            )
+            .unwrap()
+        };
+        std::fs::create_dir_all(origin.join("bin")).unwrap();
+        std::fs::write(origin.join("a.txt"), "hello").unwrap();
+        std::fs::write(origin.join("bin/run.sh"), "echo").unwrap();
+        std::fs::set_permissions(
+            origin.join("bin/run.sh"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("../a.txt", origin.join("bin/a.txt")).unwrap();
+        std::fs::write(origin.join("subst.txt"), "$Format:%H$").unwrap();
+        std::fs::write(
+            origin.join(".gitattributes"),
+            "a.txt export-ignore\nsubst.txt export-subst\n",
+        )
+        .unwrap();
+        work(&["init", "-q"]);
+        work(&["add", "."]);
+        work(&["commit", "-q", "-m", "init"]);
+
+        let cache = dir.path().join("cache");
+        let url = origin.to_str().unwrap();
+        let (repo, commit) = git::fetch(&cache, url, "HEAD", false, false, None).unwrap();
+        let dst = dir.path().join("dst");
+        git::checkout(&repo, &commit, &dst, 1 << 20, None).unwrap();
+        assert_eq!(std::fs::read(dst.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(
+            std::fs::read(dst.join("subst.txt")).unwrap(),
+            b"$Format:%H$"
+        );
+        assert_eq!(
+            std::fs::read_link(dst.join("bin/a.txt")).unwrap(),
+            Path::new("../a.txt")
+        );
+        let mode = std::fs::metadata(dst.join("bin/run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        // symlinks out of the checkout are rejected
+        std::os::unix::fs::symlink("../../etc/passwd", origin.join("passwd")).unwrap();
+        work(&["add", "passwd"]);
+        work(&["commit", "-q", "-m", "escape"]);
+        let (repo, commit) = git::fetch(&cache, url, "HEAD", false, false, None).unwrap();
+        let err = git::checkout(&repo, &commit, &dir.path().join("escaped"), 1 << 20, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("outside of extraction directory"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn copy_local_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/a.txt"), "a").unwrap();
+        std::fs::write(src.join("b.txt"), "b").unwrap();
+        let examples = std::env::current_dir().unwrap().join("examples");
+        let mut permissions = Permissions::from_options(&crate::PermissionsOptions {
+            allow_read: Some(vec![src.clone(), examples.clone()]),
+            ..Default::default()
+        });
+
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![
+            (
+                "dir".to_string(),
+                AssetSpec::Source(format!("file://{}", src.display())),
+            ),
+            (
+                "task.yaml".to_string(),
+                AssetSpec::Source("examples/task.yaml".into()),
+            ),
+        ];
+        manager
+            .prepare(assets.into_iter().collect(), &mut permissions, |_| async {})
+            .await
+            .unwrap();
+        let path = manager.path();
+        assert_eq!(std::fs::read(path.join("dir/sub/a.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(path.join("dir/b.txt")).unwrap(), b"b");
+        assert_eq!(
+            std::fs::read(path.join("task.yaml")).unwrap(),
+            std::fs::read(examples.join("task.yaml")).unwrap()
+        );
+
+        // private files are made readable by the container user
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let set_mode = |path: &Path, mode| {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+            };
+            set_mode(&src.join("b.txt"), 0o600);
+            set_mode(&src.join("sub/a.txt"), 0o700);
+            let manager = AssetManager::new().unwrap();
+            let assets = vec![(
+                "dir".to_string(),
+                AssetSpec::Source(src.display().to_string()),
+            )];
+            manager
+                .prepare(assets.into_iter().collect(), &mut permissions, |_| async {})
+                .await
+                .unwrap();
+            let mode = |path: &Path| {
+                std::fs::metadata(manager.path().join(path))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o777
+            };
+            assert_eq!(mode(Path::new("dir/b.txt")), 0o644);
+            assert_eq!(mode(Path::new("dir/sub/a.txt")), 0o755);
+        }
+
+        // paths outside of allowed ones are denied
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![(
+            "passwd".to_string(),
+            AssetSpec::Source("/etc/passwd".into()),
+        )];
+        let result = manager
+            .prepare(assets.into_iter().collect(), &mut permissions, |_| async {})
+            .await;
+        assert!(matches!(result, Err(Error::PermissionDeniedError(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reject_escaping_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink("../../secret.txt", src.join("sub/link")).unwrap();
+        let mut permissions = Permissions::from_options(&crate::PermissionsOptions {
+            allow_read: Some(vec![src.clone()]),
+            ..Default::default()
+        });
+
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![(
+            "dir".to_string(),
+            AssetSpec::Source(src.display().to_string()),
+        )];
+        let result = manager
+            .prepare(assets.into_iter().collect(), &mut permissions, |_| async {})
+            .await;
+        assert!(matches!(result, Err(Error::PermissionDeniedError(_))));
+        assert!(!manager.path().join("dir/sub/link").exists());
+
+        // symlinks staying inside allowed paths are followed
+        std::fs::remove_file(src.join("sub/link")).unwrap();
+        std::os::unix::fs::symlink("../a.txt", src.join("sub/link")).unwrap();
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![(
+            "dir".to_string(),
+            AssetSpec::Source(src.display().to_string()),
+        )];
+        manager
+            .prepare(assets.into_iter().collect(), &mut permissions, |_| async {})
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(manager.path().join("dir/sub/link")).unwrap(),
+            b"a"
+        );
+    }
+
+    #[test]
+    fn resolve_inline_content() {
+        let data = |yaml: &str| {
+            let spec = serde_yaml::from_str(yaml).unwrap();
+            match resolve("data".into(), spec, &mut Permissions::default()) {
+                Ok(Job {
+                    source: Source::Data(data),
+                    ..
+                }) => Ok(data),
+                Ok(_) => panic!("not inline content: {}", yaml),
+                Err(e) => Err(e),
+            }
+        };
+        assert_eq!(data("content: hello").unwrap(), b"hello");
+        assert_eq!(
+            data("content: aGVsbG8=\nencoding: base64").unwrap(),
+            b"hello"
+        );
+        // long base64 content is usually wrapped
+        assert_eq!(
+            data("content: |\n  aGVs\n  bG8g\n  d29y\n  bGQ=\nencoding: base64").unwrap(),
+            b"hello world"
+        );
+
+        let err = data("content: not base64!\nencoding: base64").unwrap_err();
+        assert!(
+            matches!(&err, Error::SpecError(e) if e.contains("invalid base64")),
+            "{}",
+            err
+        );
+        for yaml in ["url: a.txt\ncontent: hello", "extract: true"] {
+            let err = data(yaml).unwrap_err();
+            assert!(
+                matches!(&err, Error::SpecError(e) if e.contains("either url or content")),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn parse_mode_and_owner() {
+        assert_eq!("0755".parse::<FileMode>().unwrap(), FileMode(0o755));
+        assert_eq!("0o4750".parse::<FileMode>().unwrap(), FileMode(0o4750));
+        assert!("0789".parse::<FileMode>().is_err());
+        assert!("17777".parse::<FileMode>().is_err());
+        assert_eq!(
+            "1000".parse::<Owner>().unwrap(),
+            Owner {
+                uid: 1000,
+                gid: None
+            }
+        );
+        assert_eq!(
+            " 1000 : 100 ".parse::<Owner>().unwrap(),
+            Owner {
+                uid: 1000,
+                gid: Some(100)
+            }
+        );
+        assert!("root".parse::<Owner>().is_err());
+        assert!("1000:".parse::<Owner>().is_err());
+
+        let options: AssetOptions =
+            serde_yaml::from_str("url: a.txt\nmode: \"0640\"\nowner: 1000").unwrap();
+        assert_eq!(options.mode, Some(FileMode(0o640)));
+        assert_eq!(
+            options.owner,
+            Some(Owner {
+                uid: 1000,
+                gid: None
+            })
+        );
+        // YAML reads 0640 as an octal number
+        let options: AssetOptions =
+            serde_yaml::from_str("url: a.txt\nmode: 0640\nowner: 1000:100").unwrap();
+        assert_eq!(options.mode, Some(FileMode(0o640)));
+        assert_eq!(
+            options.owner,
+            Some(Owner {
+                uid: 1000,
+                gid: Some(100)
+            })
+        );
+        assert!(serde_yaml::from_str::<AssetOptions>("url: a.txt\nmode: 0o17777").is_err());
+        assert_eq!(
+            serde_yaml::to_string(&FileMode(0o755)).unwrap().trim(),
+            "---\n\"0755\""
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set_mode_and_owner() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let tree = dir.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::write(tree.join("sub/b.txt"), "b").unwrap();
+        // changing owner requires root
+        let owner = (crate::cache::current_uid() == 0).then_some(Owner {
+            uid: 1234,
+            gid: Some(5678),
+        });
+        let metadata = |path: &Path| std::fs::symlink_metadata(path).unwrap();
+
+        set_attributes(&file, Some(FileMode(0o600)), owner).unwrap();
+        assert_eq!(metadata(&file).permissions().mode() & 0o7777, 0o600);
+
+        let dir_mode = metadata(&tree.join("sub")).permissions().mode();
+        set_attributes(&tree, Some(FileMode(0o640)), owner).unwrap();
+        assert_eq!(metadata(&tree.join("sub/b.txt")).mode() & 0o7777, 0o640);
+        // directories stay accessible
+        assert_eq!(metadata(&tree.join("sub")).permissions().mode(), dir_mode);
+
+        if let Some(owner) = owner {
+            for path in [file, tree.clone(), tree.join("sub"), tree.join("sub/b.txt")] {
+                assert_eq!(metadata(&path).uid(), owner.uid, "{:?}", path);
+                assert_eq!(Some(metadata(&path).gid()), owner.gid, "{:?}", path);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn fork_with_attributes() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![(
+            "hello".to_string(),
+            AssetSpec::Detailed(AssetOptions {
+                content: Some("hello".into()),
+                mode: Some(FileMode(0o750)),
+                ..Default::default()
+            }),
+        )];
+        manager
+            .prepare(
+                assets.into_iter().collect(),
+                &mut Permissions::default(),
+                |_| async {},
+            )
+            .await
+            .unwrap();
+        let dir = manager.path().join("dir");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o701)).unwrap();
+        std::os::unix::fs::symlink("../hello", dir.join("link")).unwrap();
+        if crate::cache::current_uid() == 0 {
+            let owner = Some(Owner {
+                uid: 1234,
+                gid: Some(5678),
+            });
+            set_attributes(manager.path(), None, owner).unwrap();
+        }
+
+        let forked = manager.fork().unwrap();
+        for path in ["hello", "dir", "dir/link"] {
+            let original = std::fs::symlink_metadata(manager.path().join(path)).unwrap();
+            let copied = std::fs::symlink_metadata(forked.path().join(path)).unwrap();
+            assert_eq!(copied.mode(), original.mode(), "{}", path);
+            assert_eq!(copied.uid(), original.uid(), "{}", path);
+            assert_eq!(copied.gid(), original.gid(), "{}", path);
+        }
+        assert_eq!(
+            std::fs::read(forked.path().join("dir/link")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_unverified_content() {
+        let manager = AssetManager::new().unwrap();
+        let assets = vec![(
+            "hello".to_string(),
+            content("hello", &hex::encode([0u8; 32])),
+        )];
+        let result = manager
+            .prepare(
+                assets.into_iter().collect(),
+                &mut Permissions::default(),
+                |_| async {},
+            )
+            .await;
+        assert!(matches!(result, Err(Error::IntegrityError { .. })));
+        assert!(!manager.path().join("hello").exists());
+    }
+}
@@ -0,0 +1,98 @@
+//! Audit trail of permission decisions.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// A permission decision made while running a task.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+impl AuditRecord {
+    pub fn new(event: AuditEvent) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            event,
+        }
+    }
+}
+
+/// What has been decided.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// Host path mounted into container.
+    Mount {
+        /// Path as requested by task.
+        source: String,
+        /// Path after resolving symlinks, if it exists.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolved: Option<String>,
+        target: String,
+        access: MountAccess,
+    },
+    /// Network access for building or running.
+    Network {
+        phase: NetworkPhase,
+        access: NetworkAccess,
+    },
+    /// Docker volume mounted into container.
+    Volume {
+        name: String,
+        target: String,
+        access: MountAccess,
+    },
+    /// Local file or directory used as asset.
+    Asset {
+        source: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolved: Option<String>,
+        granted: bool,
+    },
+    /// Local file read as secret, without its value.
+    Secret {
+        name: String,
+        source: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolved: Option<String>,
+        granted: bool,
+    },
+    /// Host environment variable forwarded into container.
+    Env { name: String, granted: bool },
+    /// Container image used.
+    Image { image: String, granted: bool },
+    /// Permission declared as required by task.
+    Requirement {
+        permission: String,
+        target: String,
+        granted: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MountAccess {
+    ReadWrite,
+    ReadOnly,
+    Denied,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkPhase {
+    Build,
+    Run,
+}
+
+/// How a container may access network.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkAccess {
+    Enabled,
+    /// Only listed hosts are reachable through a filtering proxy.
+    Filtered(Vec<String>),
+    Disabled,
+}
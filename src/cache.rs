@@ -0,0 +1,587 @@
+//! Cache of downloaded assets shared between runs.
+
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use reqwest::{blocking::Client, header, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{
+    asset::{git, SizeCounter},
+    Error,
+};
+
+/// Where and how downloaded assets (HTTP resources and git repositories) are
+/// cached between runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssetCacheConfig {
+    /// Root directory of the cache, can be shared by multiple runners of the
+    /// same user.
+    pub dir: PathBuf,
+    /// Maximum total size in bytes, enforced by evicting the least recently
+    /// used entries after assets are prepared.
+    pub max_size: Option<u64>,
+    /// How long cached resources are regarded fresh without checking the
+    /// remote, and kept when pruning.
+    pub ttl: Option<Duration>,
+    /// Serve assets only from cache, without any network access.
+    pub offline: bool,
+}
+
+impl Default for AssetCacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            max_size: None,
+            ttl: None,
+            offline: false,
+        }
+    }
+}
+
+/// Kind of cached assets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CacheKind {
+    Http,
+    Git,
+}
+
+/// A cached HTTP resource or git repository.
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub kind: CacheKind,
+    /// URL the entry is fetched from.
+    pub source: String,
+    pub path: PathBuf,
+    /// Size in bytes on disk.
+    pub size: u64,
+    /// When the entry is last fetched or used.
+    pub modified: SystemTime,
+}
+
+impl CacheEntry {
+    fn remove(&self) -> Result<(), Error> {
+        match self.kind {
+            CacheKind::Http => {
+                fs::remove_file(&self.path)?;
+                let meta = meta_path(&self.path);
+                if meta.exists() {
+                    fs::remove_file(meta)?;
+                }
+            }
+            CacheKind::Git => fs::remove_dir_all(&self.path)?,
+        }
+        Ok(())
+    }
+}
+
+/// Metadata written alongside each cached resource.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    resource: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+}
+
+/// Cache directory of the current user, e.g. `~/.cache/srun`.
+fn default_dir() -> PathBuf {
+    if let Some(dir) = dirs_next::cache_dir() {
+        return dir.join("srun");
+    }
+    // temporary directory is shared, keep users apart
+    #[cfg(unix)]
+    let name = format!("srun-{}", current_uid());
+    #[cfg(not(unix))]
+    let name = "srun".to_string();
+    std::env::temp_dir().join(name)
+}
+
+#[cfg(unix)]
+pub(crate) fn current_uid() -> u32 {
+    // SAFETY: geteuid never fails and has no side effects
+    unsafe { libc::geteuid() }
+}
+
+impl AssetCacheConfig {
+    /// Create the cache directory accessible to the current user only, or
+    /// check that an existing one is owned by the current user and not
+    /// writable by others, who could otherwise plant cached content.
+    pub(crate) fn ensure_dir(&self) -> Result<(), Error> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+            if !self.dir.exists() {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(&self.dir)?;
+            }
+            let metadata = fs::metadata(&self.dir)?;
+            if metadata.uid() != current_uid() || metadata.mode() & 0o022 != 0 {
+                return Err(Error::CacheError(format!(
+                    "cache directory {} should be owned by the current user and not writable \
+                     by others",
+                    self.dir.display()
+                )));
+            }
+        }
+        #[cfg(not(unix))]
+        fs::create_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    pub(crate) fn http_dir(&self) -> PathBuf {
+        self.dir.join("http")
+    }
+
+    pub(crate) fn git_dir(&self) -> PathBuf {
+        self.dir.join("git")
+    }
+
+    /// Get cached path of the resource at `url`, downloading it if necessary.
+    /// Downloaded bytes are counted by `counter` as they arrive, so that the
+    /// download stops as soon as a size limit is exceeded. This is blocking.
+    pub(crate) fn fetch(
+        &self,
+        url: &str,
+        timeout: Option<Duration>,
+        counter: &mut SizeCounter,
+    ) -> Result<PathBuf, Error> {
+        self.ensure_dir()?;
+        let dir = self.http_dir();
+        let path = dir.join(hex::encode(Sha256::digest(url.as_bytes())));
+        let meta_path = meta_path(&path);
+        let meta = fs::read(&meta_path)
+            .ok()
+            .and_then(|m| serde_json::from_slice::<Meta>(&m).ok())
+            .filter(|_| path.exists());
+
+        if let Some(meta) = &meta {
+            // metadata is written whenever the resource is validated, while
+            // the resource itself is touched whenever it is used
+            let fresh = match (self.ttl, fs::metadata(&meta_path)?.modified()?.elapsed()) {
+                (Some(ttl), Ok(age)) => age <= ttl,
+                _ => false,
+            };
+            if fresh || self.offline {
+                log::debug!("using cached {}: {:?}", url, path);
+                return self.hit(&path, counter);
+            }
+            log::debug!("revalidating cached {} with etag {:?}", url, meta.etag);
+        } else if self.offline {
+            return Err(Error::CacheError(format!("{} is not cached", url)));
+        }
+
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| Error::CacheError(e.to_string()))?;
+        let mut request = client.get(url);
+        if let Some(etag) = meta.as_ref().and_then(|m| m.etag.as_ref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let mut response = request
+            .send()
+            .map_err(|e| Error::CacheError(e.to_string()))?;
+        if let (StatusCode::NOT_MODIFIED, Some(meta)) = (response.status(), &meta) {
+            write_meta(&meta_path, meta)?;
+            return self.hit(&path, counter);
+        }
+        if !response.status().is_success() {
+            return Err(Error::CacheError(format!(
+                "failed to download {}: {}",
+                url,
+                response.status()
+            )));
+        }
+        // fail early without reading anything if the size is known
+        if let Some(len) = response.content_length() {
+            counter.check(len)?;
+        }
+
+        // download to a temporary file, so that partial downloads are never
+        // taken as cached
+        fs::create_dir_all(&dir)?;
+        let mut file = NamedTempFile::new_in(&dir)?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut buf = vec![0; 64 << 10];
+        loop {
+            if matches!(deadline, Some(deadline) if Instant::now() > deadline) {
+                return Err(Error::AssetLimitError(format!(
+                    "downloading {} timed out",
+                    url
+                )));
+            }
+            let n = response.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            counter.add(n as u64)?;
+            file.write_all(&buf[..n])?;
+        }
+        file.flush()?;
+        // temporary files are private, but assets are read by container users
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.as_file()
+                .set_permissions(fs::Permissions::from_mode(0o644))?;
+        }
+
+        let meta = Meta {
+            resource: url.into(),
+            etag: response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(Into::into),
+        };
+        file.persist(&path).map_err(|e| Error::IOError(e.error))?;
+        write_meta(&meta_path, &meta)?;
+        Ok(path)
+    }
+
+    /// Use a cached resource, counting its size and marking it as recently
+    /// used.
+    fn hit(&self, path: &Path, counter: &mut SizeCounter) -> Result<PathBuf, Error> {
+        counter.add(fs::metadata(path)?.len())?;
+        File::options()
+            .append(true)
+            .open(path)?
+            .set_modified(SystemTime::now())?;
+        Ok(path.into())
+    }
+
+    /// List all cached entries.
+    pub fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        let mut entries = vec![];
+
+        for entry in read_dir(&self.http_dir())? {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // temporary files of downloads in progress start with `.`
+            if !entry.file_type()?.is_file()
+                || name.starts_with('.')
+                || name.ends_with(".meta")
+                || name.ends_with(".lock")
+            {
+                continue;
+            }
+            let meta = meta_path(&path);
+            let source = fs::read(&meta)
+                .ok()
+                .and_then(|m| serde_json::from_slice::<Meta>(&m).ok())
+                .map(|m| m.resource)
+                .unwrap_or_default();
+            let metadata = entry.metadata()?;
+            entries.push(CacheEntry {
+                kind: CacheKind::Http,
+                source,
+                size: metadata.len() + fs::metadata(&meta).map(|m| m.len()).unwrap_or(0),
+                modified: metadata.modified()?,
+                path,
+            });
+        }
+
+        for entry in read_dir(&self.git_dir())? {
+            let path = entry.path();
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let modified = fs::metadata(path.join(git::USED_MARKER))
+                .or_else(|_| entry.metadata())?
+                .modified()?;
+            entries.push(CacheEntry {
+                kind: CacheKind::Git,
+                source: git::git(&path, &["config", "srun.url"]).unwrap_or_default(),
+                size: dir_size(&path)?,
+                modified,
+                path,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Remove entries older than `ttl`, then the least recently used ones
+    /// until the cache fits in `max_size`. Returns the removed entries.
+    pub fn prune(&self) -> Result<Vec<CacheEntry>, Error> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|e| std::cmp::Reverse(e.modified));
+        let now = SystemTime::now();
+        let mut total = 0;
+        let mut removed = vec![];
+        for entry in entries {
+            let expired = match (self.ttl, now.duration_since(entry.modified)) {
+                (Some(ttl), Ok(age)) => age > ttl,
+                _ => false,
+            };
+            let oversized = matches!(self.max_size, Some(max) if total + entry.size > max);
+            if expired || oversized {
+                log::debug!("removing cached {}: {:?}", entry.source, entry.path);
+                entry.remove()?;
+                removed.push(entry);
+            } else {
+                total += entry.size;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove all cached entries.
+    pub fn clear(&self) -> Result<(), Error> {
+        for dir in [self.http_dir(), self.git_dir()] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of the metadata file of a cached resource.
+fn meta_path(resource: &Path) -> PathBuf {
+    let mut path = resource.as_os_str().to_owned();
+    path.push(".meta");
+    PathBuf::from(path)
+}
+
+fn write_meta(path: &Path, meta: &Meta) -> Result<(), Error> {
+    let meta = serde_json::to_vec(meta).map_err(|e| Error::CacheError(e.to_string()))?;
+    Ok(fs::write(path, meta)?)
+}
+
+/// Entries of a directory, which may not exist yet.
+fn read_dir(dir: &Path) -> Result<Vec<fs::DirEntry>, Error> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    Ok(fs::read_dir(dir)?.collect::<Result<_, _>>()?)
+}
+
+fn dir_size(dir: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::TcpListener,
+        sync::{atomic::AtomicU64, Arc},
+        thread::JoinHandle,
+    };
+
+    use super::*;
+    use crate::asset::AssetLimits;
+
+    fn config(dir: &Path) -> AssetCacheConfig {
+        AssetCacheConfig {
+            dir: dir.join("cache"),
+            ..Default::default()
+        }
+    }
+
+    fn counter(max_size: Option<u64>) -> SizeCounter {
+        let limits = AssetLimits {
+            max_size,
+            ..Default::default()
+        };
+        SizeCounter::new("file", &limits, Arc::new(AtomicU64::new(0)))
+    }
+
+    /// Serve one HTTP response per connection, returning the requests.
+    fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(String::from_utf8(request).unwrap().to_lowercase());
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    const HELLO: &str = "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\
+                         Connection: close\r\n\r\nhello";
+    const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n";
+
+    #[test]
+    fn revalidate_with_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let (url, server) = serve(vec![HELLO, NOT_MODIFIED]);
+
+        let path = config.fetch(&url, None, &mut counter(None)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o644);
+        }
+
+        let mut cached = counter(None);
+        assert_eq!(config.fetch(&url, None, &mut cached).unwrap(), path);
+        assert_eq!(cached.size(), 5);
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+
+        let entries = config.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, CacheKind::Http);
+        assert_eq!(entries[0].source, url);
+        assert_eq!(entries[0].path, path);
+    }
+
+    #[test]
+    fn use_fresh_and_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path());
+        let (url, server) = serve(vec![HELLO]);
+
+        config.offline = true;
+        let err = config.fetch(&url, None, &mut counter(None)).unwrap_err();
+        assert!(err.to_string().contains("is not cached"));
+
+        config.offline = false;
+        config.ttl = Some(Duration::from_secs(3600));
+        let path = config.fetch(&url, None, &mut counter(None)).unwrap();
+        // served from cache without any request, as the server only answers
+        // once
+        assert_eq!(config.fetch(&url, None, &mut counter(None)).unwrap(), path);
+        config.ttl = None;
+        config.offline = true;
+        assert_eq!(config.fetch(&url, None, &mut counter(None)).unwrap(), path);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stop_downloading_over_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        let (url, server) = serve(vec![
+            HELLO,
+            // size is only known while reading
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello",
+        ]);
+        for _ in 0..2 {
+            let err = config.fetch(&url, None, &mut counter(Some(4))).unwrap_err();
+            assert!(matches!(err, Error::AssetLimitError(_)), "{:?}", err);
+        }
+        server.join().unwrap();
+        assert!(config.entries().unwrap().is_empty());
+        assert_eq!(fs::read_dir(config.http_dir()).unwrap().count(), 0);
+    }
+
+    /// Write a cached resource of `size` bytes last used `age` ago.
+    fn put(config: &AssetCacheConfig, name: &str, size: usize, age: u64) {
+        let dir = config.http_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, vec![0; size]).unwrap();
+        let meta = Meta {
+            resource: name.into(),
+            etag: None,
+        };
+        write_meta(&meta_path(&path), &meta).unwrap();
+        File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+    }
+
+    fn sources(entries: &[CacheEntry]) -> Vec<&str> {
+        let mut sources = entries
+            .iter()
+            .map(|e| e.source.as_str())
+            .collect::<Vec<_>>();
+        sources.sort_unstable();
+        sources
+    }
+
+    #[test]
+    fn prune_and_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path());
+        put(&config, "new", 100, 10);
+        put(&config, "old", 100, 20);
+        put(&config, "older", 100, 30);
+        put(&config, "expired", 100, 1000);
+        let entries = config.entries().unwrap();
+        assert_eq!(sources(&entries), vec!["expired", "new", "old", "older"]);
+        let size = entries[0].size;
+        assert!(size > 100);
+
+        // nothing to prune without limits
+        assert!(config.prune().unwrap().is_empty());
+
+        config.ttl = Some(Duration::from_secs(100));
+        config.max_size = Some(2 * size);
+        let removed = config.prune().unwrap();
+        assert_eq!(sources(&removed), vec!["expired", "older"]);
+        assert_eq!(sources(&config.entries().unwrap()), vec!["new", "old"]);
+        assert!(!meta_path(&config.http_dir().join("older")).exists());
+
+        config.clear().unwrap();
+        assert!(config.entries().unwrap().is_empty());
+        assert!(!config.http_dir().exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_foreign_cache_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path());
+        config.ensure_dir().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&config.dir), 0o700);
+
+        fs::set_permissions(&config.dir, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(config.ensure_dir().is_err());
+        fs::set_permissions(&config.dir, fs::Permissions::from_mode(0o755)).unwrap();
+        config.ensure_dir().unwrap();
+
+        // only root can hand the directory to someone else
+        if current_uid() == 0 {
+            std::os::unix::fs::chown(&config.dir, Some(65534), None).unwrap();
+            let err = config.ensure_dir().unwrap_err();
+            assert!(err.to_string().contains("owned by the current user"));
+        }
+    }
+}
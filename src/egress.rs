@@ -0,0 +1,270 @@
+//! Filtered network egress through an srun-managed proxy container.
+
+use std::{fs::File, io::Write, time::Duration};
+
+use bollard::{
+    container::{Config, CreateContainerOptions, RemoveContainerOptions},
+    exec::{CreateExecOptions, StartExecResults},
+    image::CreateImageOptions,
+    models::{EndpointSettings, HostConfig},
+    network::{ConnectNetworkOptions, CreateNetworkOptions},
+    Docker,
+};
+use futures::StreamExt;
+use tempfile::TempDir;
+
+use crate::{permission::NetDescriptor, Error};
+
+const PROXY_IMAGE: &str = "ubuntu/squid:latest";
+const PROXY_ALIAS: &str = "srun-proxy";
+const PROXY_PORT: u16 = 3128;
+/// How many times to check whether the proxy is listening, with
+/// [`READY_INTERVAL`] in between.
+const READY_RETRIES: u32 = 100;
+const READY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A per-run internal docker network whose only way out is a filtering proxy.
+///
+/// Containers attached to [`EgressProxy::network`] can only reach the hosts
+/// given on start, by using the proxy given in [`EgressProxy::envs`].
+pub(crate) struct EgressProxy<'docker> {
+    docker: &'docker Docker,
+    network: String,
+    container: String,
+    // keeps proxy config alive while the proxy is running
+    _config_dir: TempDir,
+}
+
+impl<'docker> EgressProxy<'docker> {
+    /// Create the network and start the proxy allowing only `hosts`.
+    pub async fn start(
+        docker: &'docker Docker,
+        hosts: &[NetDescriptor],
+    ) -> Result<EgressProxy<'docker>, Error> {
+        let config_dir = tempfile::tempdir()?;
+        let config_path = config_dir.path().join("squid.conf");
+        {
+            log::debug!("writing proxy config at: {:?}", config_path);
+            let mut file = File::create(&config_path)?;
+            write!(file, "{}", squid_config(hosts))?;
+            file.flush()?;
+        }
+
+        let mut stream = docker.create_image(
+            Some(CreateImageOptions {
+                from_image: PROXY_IMAGE,
+                ..Default::default()
+            }),
+            None,
+            None,
+        );
+        while let Some(info) = stream.next().await {
+            log::trace!("pulling proxy image: {:?}", info?);
+        }
+
+        let name = format!(
+            "srun-{}-{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos()
+        );
+        docker
+            .create_network(CreateNetworkOptions {
+                name: name.as_str(),
+                check_duplicate: true,
+                driver: "bridge",
+                internal: true,
+                ..Default::default()
+            })
+            .await?;
+        log::info!("created internal network: {}", name);
+
+        let proxy = Self {
+            docker,
+            network: name.clone(),
+            container: name.clone(),
+            _config_dir: config_dir,
+        };
+
+        let config = Config {
+            image: Some(PROXY_IMAGE.to_string()),
+            host_config: Some(HostConfig {
+                binds: Some(vec![format!(
+                    "{}:/etc/squid/squid.conf:ro",
+                    config_path
+                        .to_str()
+                        .expect("path should always be valid utf-8 string")
+                )]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let started = async {
+            docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: proxy.container.as_str(),
+                    }),
+                    config,
+                )
+                .await?;
+            docker
+                .connect_network(
+                    &proxy.network,
+                    ConnectNetworkOptions {
+                        container: proxy.container.as_str(),
+                        endpoint_config: EndpointSettings {
+                            aliases: Some(vec![PROXY_ALIAS.to_string()]),
+                            ..Default::default()
+                        },
+                    },
+                )
+                .await?;
+            docker
+                .start_container::<String>(&proxy.container, None)
+                .await?;
+            proxy.wait_ready().await
+        }
+        .await;
+
+        match started {
+            Ok(()) => {
+                log::info!("egress proxy started: {}", proxy.container);
+                Ok(proxy)
+            }
+            Err(e) => {
+                proxy.stop().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Wait until squid listens on the proxy port, so that requests made
+    /// early in the stage script do not fail.
+    async fn wait_ready(&self) -> Result<(), Error> {
+        let probe = format!("echo > /dev/tcp/127.0.0.1/{}", PROXY_PORT);
+        for _ in 0..READY_RETRIES {
+            let exec = self
+                .docker
+                .create_exec(
+                    &self.container,
+                    CreateExecOptions {
+                        cmd: Some(vec!["bash", "-c", probe.as_str()]),
+                        attach_stdout: Some(true),
+                        attach_stderr: Some(true),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            if let StartExecResults::Attached { mut output, .. } =
+                self.docker.start_exec(&exec.id, None).await?
+            {
+                while let Some(line) = output.next().await {
+                    log::trace!("probing proxy: {:?}", line?);
+                }
+            }
+            if self.docker.inspect_exec(&exec.id).await?.exit_code == Some(0) {
+                log::debug!("egress proxy is listening on port {}", PROXY_PORT);
+                return Ok(());
+            }
+            tokio::time::sleep(READY_INTERVAL).await;
+        }
+        Err(Error::UnknownError(format!(
+            "egress proxy is not listening on port {}",
+            PROXY_PORT
+        )))
+    }
+
+    /// Name of the internal network to attach containers to.
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    /// Proxy environment variables for containers in the network.
+    pub fn envs(&self) -> Vec<(String, String)> {
+        let url = format!("http://{}:{}", PROXY_ALIAS, PROXY_PORT);
+        ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"]
+            .iter()
+            .map(|k| (k.to_string(), url.clone()))
+            .collect()
+    }
+
+    /// Remove the proxy container and the network.
+    pub async fn stop(self) -> Result<(), Error> {
+        log::info!("removing egress proxy: {}", self.container);
+        if let Err(e) = self
+            .docker
+            .remove_container(
+                &self.container,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+        {
+            log::warn!("failed to remove proxy container: {:?}", e);
+        }
+        self.docker.remove_network(&self.network).await?;
+        Ok(())
+    }
+}
+
+fn squid_config(hosts: &[NetDescriptor]) -> String {
+    let mut conf = format!("http_port {}\n", PROXY_PORT);
+    for (i, host) in hosts.iter().enumerate() {
+        let (name, port) = host.split();
+        match name.strip_prefix("*.") {
+            // `.example.com` in squid also matches `example.com`, so match
+            // subdomains only with a regex, as `NetDescriptor` does. `-n`
+            // disables reverse lookups of IP addresses, whose PTR records are
+            // controlled by their owners.
+            Some(domain) => {
+                conf += &format!(
+                    "acl allow_{} dstdom_regex -n -i \\.{}$\n",
+                    i,
+                    escape_regex(domain)
+                );
+            }
+            None => conf += &format!("acl allow_{} dstdomain -n {}\n", i, name),
+        }
+        if let Some(port) = port {
+            conf += &format!("acl allow_{}_port port {}\n", i, port);
+            conf += &format!("http_access allow allow_{} allow_{}_port\n", i, i);
+        } else {
+            conf += &format!("http_access allow allow_{}\n", i);
+        }
+    }
+    conf += "http_access deny all\ncache deny all\n";
+    conf
+}
+
+/// Escape characters with special meaning in (POSIX extended) regex.
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if !c.is_ascii_alphanumeric() && c != '-' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_excludes_apex() {
+        let conf = squid_config(&[
+            NetDescriptor("*.example.com".into()),
+            NetDescriptor("example.org:443".into()),
+        ]);
+        assert!(conf.contains("acl allow_0 dstdom_regex -n -i \\.example\\.com$\n"));
+        assert!(conf.contains("http_access allow allow_0\n"));
+        assert!(conf.contains("acl allow_1 dstdomain -n example.org\n"));
+        assert!(conf.contains("acl allow_1_port port 443\n"));
+        assert!(conf.contains("http_access allow allow_1 allow_1_port\n"));
+        assert!(conf.ends_with("http_access deny all\ncache deny all\n"));
+    }
+}
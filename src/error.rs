@@ -15,8 +15,24 @@ pub enum Error {
     #[error("Permission denied: {0}.")]
     PermissionDeniedError(String),
 
-    #[error("Error in cache system: {0:?}.")]
-    CacheError(cached_path::Error),
+    #[error("Error while extracting archive: {0}.")]
+    ArchiveError(String),
+
+    #[error("Asset limit exceeded: {0}.")]
+    AssetLimitError(String),
+
+    #[error("Error while fetching git repository: {0}.")]
+    GitError(String),
+
+    #[error("Integrity check failed for {asset}: expected {expected}, got {actual}.")]
+    IntegrityError {
+        asset: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Error in cache system: {0}.")]
+    CacheError(String),
 
     #[error("Script exited with code {0}.")]
     ErrorCode(u64),
@@ -41,12 +57,6 @@ impl From<std::io::Error> for Error {
     }
 }
 
-impl From<cached_path::Error> for Error {
-    fn from(e: cached_path::Error) -> Self {
-        Error::CacheError(e)
-    }
-}
-
 impl From<hyper::Error> for Error {
     fn from(e: hyper::Error) -> Self {
         Error::ConnectionError(e)
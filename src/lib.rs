@@ -4,18 +4,35 @@
 //! remote runner service.
 
 mod asset;
+pub mod audit;
+pub mod cache;
+mod egress;
 mod error;
 mod permission;
+mod policy;
 mod reporter;
 pub mod runner;
 pub mod sandbox;
+mod secret;
+mod security;
+mod size;
 mod task;
 
-pub use asset::AssetManager;
+pub use asset::{
+    AssetLimits, AssetManager, AssetOptions, AssetSpec, ContentEncoding, FileMode, Owner,
+};
+pub use cache::AssetCacheConfig;
 pub use error::Error;
+pub use permission::PermissionRequirements;
 pub use permission::Permissions;
 pub use permission::PermissionsOptions;
-pub use reporter::Reporter;
+pub use policy::Policy;
+pub use reporter::{
+    AsyncReporter, ChannelReporter, JsonReporter, ReportContext, ReportEvent, Reporter,
+};
 pub use runner::Runner;
 pub use sandbox::Sandbox;
+pub use secret::{Secret, SecretSource, SecretSpec, SecretTarget, Secrets};
+pub use security::{ResourceLimits, SecurityProfile};
+pub use size::ByteSize;
 pub use task::Task;
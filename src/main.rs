@@ -1,15 +1,24 @@
 use std::fs;
-use std::{convert::TryInto, path::PathBuf};
+use std::{
+    convert::TryInto,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use anyhow::{Context, Result};
-use clap::{App, Arg};
-use srun::{Permissions, PermissionsOptions, Runner, Task};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use srun::{
+    AssetCacheConfig, AsyncReporter, ByteSize, JsonReporter, Permissions, PermissionsOptions,
+    Policy, Runner, SecurityProfile, Task,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
 
     let matches = App::new("srun")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::new("INPUT")
                 .about("Input yaml file describing the task")
@@ -18,8 +27,50 @@ async fn main() -> Result<()> {
         )
         .arg(
             Arg::new("allow-net")
-                .about("Allow network access")
-                .long("--allow-net"),
+                .about("Allow network access, optionally to the given hosts only")
+                .long("--allow-net")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::new("allow-build-net")
+                .about(
+                    "Allow network access while building images from `extend`, optionally to the \
+                     given hosts only. Builds have no network access by default",
+                )
+                .long("--allow-build-net")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::new("allow-env")
+                .about("Allow forwarding host environment variables")
+                .long("--allow-env")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::new("allow-volume")
+                .about("Allow mounting docker volumes")
+                .long("--allow-volume")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .use_delimiter(true),
+        )
+        .arg(
+            Arg::new("allow-image")
+                .about("Allow only images matching the given patterns")
+                .long("--allow-image")
+                .takes_value(true)
+                .require_equals(true)
+                .use_delimiter(true),
         )
         .arg(
             Arg::new("allow-read")
@@ -35,8 +86,103 @@ async fn main() -> Result<()> {
                 .takes_value(true)
                 .multiple_values(true),
         )
+        .arg(
+            Arg::new("deny-read")
+                .about("Deny read access")
+                .long("--deny-read")
+                .takes_value(true)
+                .multiple_values(true),
+        )
+        .arg(
+            Arg::new("deny-write")
+                .about("Deny write access")
+                .long("--deny-write")
+                .takes_value(true)
+                .multiple_values(true),
+        )
+        .arg(
+            Arg::new("policy")
+                .about("Permission policy file (yaml or toml), merged with flags")
+                .long("--policy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("no-prompt")
+                .about("Fail instead of prompting for permissions not granted")
+                .long("--no-prompt"),
+        )
+        .arg(
+            Arg::new("no-hardening")
+                .about("Run containers with docker default security options")
+                .long("--no-hardening"),
+        )
+        .arg(
+            Arg::new("format")
+                .about("Output format")
+                .long("--format")
+                .takes_value(true)
+                .possible_values(["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .about("Directory where downloaded assets are cached, ~/.cache/srun by default")
+                .long("--cache-dir")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("cache-max-size")
+                .about("Maximum size of asset cache, e.g. 10g")
+                .long("--cache-max-size")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("cache-ttl")
+                .about("Seconds for which cached assets are regarded fresh")
+                .long("--cache-ttl")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("offline")
+                .about("Use cached assets only, without downloading")
+                .long("--offline"),
+        )
+        .subcommand(
+            App::new("cache")
+                .about("Manage asset cache")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(App::new("ls").about("List cached assets"))
+                .subcommand(
+                    App::new("prune")
+                        .about("Remove cached assets exceeding max size or older than ttl"),
+                )
+                .subcommand(App::new("clear").about("Remove all cached assets")),
+        )
         .get_matches();
 
+    let mut cache = AssetCacheConfig {
+        offline: matches.is_present("offline"),
+        ..Default::default()
+    };
+    if let Some(dir) = matches.value_of("cache-dir") {
+        cache.dir = PathBuf::from(dir);
+    }
+    if let Some(size) = matches.value_of("cache-max-size") {
+        cache.max_size = Some(size.parse::<ByteSize>()?.0);
+    }
+    if let Some(ttl) = matches.value_of("cache-ttl") {
+        cache.ttl = Some(Duration::from_secs(
+            ttl.parse().context("invalid cache ttl")?,
+        ));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("cache") {
+        return manage_cache(&cache, matches);
+    }
+
     let file = matches
         .value_of("INPUT")
         .context("task script not provided")?;
@@ -46,39 +192,111 @@ async fn main() -> Result<()> {
 
     let docker = bollard::Docker::connect_with_socket_defaults()?;
 
-    let permissions = Permissions::from_options(&PermissionsOptions {
-        allow_read: matches.values_of("allow-read").map(|e| {
-            e.map(|v| {
-                PathBuf::from(v)
-                    .canonicalize()
-                    .context(format!("path {} not exist", v))
-                    .unwrap()
-            })
-            .collect()
-        }),
-        allow_write: matches.values_of("allow-write").map(|e| {
-            e.map(|v| {
-                PathBuf::from(v)
-                    .canonicalize()
-                    .context(format!("path {} not exist", v))
-                    .unwrap()
-            })
-            .collect()
-        }),
-        allow_net: matches.is_present("allow-net"),
+    let policy = match matches.value_of("policy") {
+        Some(path) => Policy::from_file(Path::new(path)).context("failed to load policy")?,
+        None => Policy::default(),
+    };
+
+    let mut options = policy.to_options().merge(&PermissionsOptions {
+        allow_read: path_option(&matches, "allow-read")?,
+        allow_write: path_option(&matches, "allow-write")?,
+        deny_read: path_option(&matches, "deny-read")?.unwrap_or_default(),
+        deny_write: path_option(&matches, "deny-write")?.unwrap_or_default(),
+        allow_net: list_option(&matches, "allow-net"),
+        allow_build_net: list_option(&matches, "allow-build-net"),
+        allow_env: list_option(&matches, "allow-env"),
+        allow_volume: list_option(&matches, "allow-volume"),
+        allow_image: list_option(&matches, "allow-image"),
+        ..Default::default()
     });
+    options.prompt = std::io::stdin().is_terminal() && !matches.is_present("no-prompt");
+    if matches.is_present("no-hardening") {
+        options.security = SecurityProfile::permissive();
+    }
+    let permissions = Permissions::from_options(&options);
 
     log::info!("run with permission: {:?}", permissions);
 
-    {
-        let mut runner = Runner::new(&docker, Some(permissions))?;
-        let r = task.run(&mut runner).await;
-        if let Err(srun::Error::ErrorCode(code)) = r {
-            std::process::exit(code.try_into().unwrap());
+    match matches.value_of("format") {
+        Some("json") => {
+            let runner = Runner::with_reporter(&docker, Some(permissions), JsonReporter)?;
+            run_task(task, runner, cache).await
         }
-        r.context("failed to run task")?;
-        // drop to ensure runner finalize gracefully
+        _ => run_task(task, Runner::new(&docker, Some(permissions))?, cache).await,
     }
+}
 
+async fn run_task(
+    task: Task,
+    mut runner: Runner<'_, impl AsyncReporter>,
+    cache: AssetCacheConfig,
+) -> Result<()> {
+    runner.set_asset_cache(cache);
+    let r = task.run(&mut runner).await;
+    if r.is_err() {
+        log::info!("task failed with {} audit records", runner.audit().len());
+    }
+    if let Err(srun::Error::ErrorCode(code)) = r {
+        std::process::exit(code.try_into().unwrap());
+    }
+    let result = r.context("failed to run task")?;
+    log::info!("task finished with {} audit records", result.audit.len());
+    Ok(())
+}
+
+/// Run `srun cache` subcommands.
+fn manage_cache(cache: &AssetCacheConfig, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand_name() {
+        Some("ls") => {
+            let now = SystemTime::now();
+            for entry in cache.entries()? {
+                let age = now
+                    .duration_since(entry.modified)
+                    .unwrap_or_default()
+                    .as_secs();
+                println!(
+                    "{:?}\t{}\t{}s\t{}",
+                    entry.kind, entry.size, age, entry.source
+                );
+            }
+        }
+        Some("prune") => {
+            for entry in cache.prune()? {
+                println!("removed {}", entry.source);
+            }
+        }
+        Some("clear") => cache.clear()?,
+        _ => unreachable!("subcommand is required"),
+    }
     Ok(())
 }
+
+/// Values of an option like `--allow-net=a,b`, where a bare `--allow-net`
+/// gives an empty list.
+fn list_option(matches: &ArgMatches, name: &str) -> Option<Vec<String>> {
+    if matches.is_present(name) {
+        Some(
+            matches
+                .values_of(name)
+                .map(|e| e.map(String::from).collect())
+                .unwrap_or_default(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Canonicalized paths given to an option like `--allow-read`.
+fn path_option(matches: &ArgMatches, name: &str) -> Result<Option<Vec<PathBuf>>> {
+    matches
+        .values_of(name)
+        .map(|e| {
+            e.map(|v| {
+                PathBuf::from(v)
+                    .canonicalize()
+                    .context(format!("path {} not exist", v))
+            })
+            .collect()
+        })
+        .transpose()
+}
@@ -1,24 +1,25 @@
 use std::{
     collections::HashSet,
     hash::Hash,
-    path::{Path, PathBuf},
+    io::{self, BufRead, Write},
+    path::{Component, Path, PathBuf},
 };
 
-use crate::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{AuditEvent, AuditRecord, MountAccess, NetworkAccess, NetworkPhase},
+    Error, Policy, ResourceLimits, SecurityProfile,
+};
 
 /// Represents whether permission is granted or denied.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
 pub enum PermissionState {
     Granted = 0,
+    #[default]
     Denied = 1,
 }
 
-impl Default for PermissionState {
-    fn default() -> Self {
-        PermissionState::Denied
-    }
-}
-
 impl PermissionState {
     fn fmt_access(name: &str, info: Option<&str>) -> String {
         format!(
@@ -42,17 +43,14 @@ impl PermissionState {
             _ => Err(Self::error(name, info)),
         }
     }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct UnitPermission {
-    pub name: &'static str,
-    pub state: PermissionState,
-}
 
-impl UnitPermission {
-    pub fn check(&self) -> Result<(), Error> {
-        self.state.check(self.name, None)
+    fn explicitly_denied(name: &str, info: Option<&str>, denied: &Path) -> Result<(), Error> {
+        Err(Error::PermissionDeniedError(format!(
+            "Requires {}, but {} is denied with --deny-{} flag",
+            Self::fmt_access(name, info),
+            denied.display(),
+            name
+        )))
     }
 }
 
@@ -62,6 +60,59 @@ pub struct UnaryPermission<T: Eq + Hash> {
     pub global_state: PermissionState,
     pub granted_list: HashSet<T>,
     pub denied_list: HashSet<T>,
+    /// Ask on the terminal before denying access.
+    pub prompt: bool,
+}
+
+impl<T: Eq + Hash> UnaryPermission<T> {
+    /// Prompt for access if prompting is enabled. Answering `y` grants the
+    /// given descriptors, and `a` grants everything for the rest of the run.
+    fn ask(&mut self, info: &str, grant: Vec<T>) -> bool {
+        if !self.prompt {
+            return false;
+        }
+        // info comes from the task, which must not rewrite the prompt
+        let message = format!(
+            "Grant {}? [y/N/a] ",
+            PermissionState::fmt_access(self.name, Some(&escape_control(info)))
+        );
+        match prompt_user(&message).as_str() {
+            "y" | "yes" => {
+                self.granted_list.extend(grant);
+                true
+            }
+            "a" | "all" => {
+                self.global_state = PermissionState::Granted;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Escape control characters, e.g. ANSI escape sequences and carriage
+/// returns, so that they are shown instead of interpreted by the terminal.
+fn escape_control(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Read an answer from stdin, in lower case. Any error counts as no answer.
+fn prompt_user(message: &str) -> String {
+    eprint!("{}", message);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return String::new();
+    }
+    answer.trim().to_lowercase()
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
@@ -70,54 +121,343 @@ pub struct ReadDescriptor(pub PathBuf);
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct WriteDescriptor(pub PathBuf);
 
-impl UnaryPermission<ReadDescriptor> {
-    pub fn check(&self, path: &Path) -> Result<(), Error> {
-        if self.global_state == PermissionState::Granted {
-            if self
-                .denied_list
+/// Name of a host environment variable.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct EnvDescriptor(pub String);
+
+/// Name of a docker volume.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct VolumeDescriptor(pub String);
+
+/// Image reference pattern, e.g. `python:*`, `ghcr.io/org/*` or
+/// `python@sha256:<digest>`.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ImageDescriptor(pub String);
+
+impl NameDescriptor for ImageDescriptor {
+    fn matches(&self, image: &str) -> bool {
+        let pattern = &self.0;
+        if let Some((name, digest)) = pattern.split_once('@') {
+            // digest pinning: only this exact image content is allowed
+            return match image.split_once('@') {
+                Some((image_name, image_digest)) => {
+                    image_digest == digest && normalize_image(name) == normalize_image(image_name)
+                }
+                None => false,
+            };
+        }
+        let image = normalize_image(image);
+        glob_match(pattern, &image) || glob_match(&qualify_name(pattern), &image)
+    }
+    fn from_name(image: &str) -> Self {
+        ImageDescriptor(image.into())
+    }
+}
+
+/// Expand image reference to its fully qualified form, e.g. `python:3` to
+/// `docker.io/library/python:3`.
+fn normalize_image(image: &str) -> String {
+    let (name, suffix) = match image.split_once('@') {
+        Some((name, digest)) => (name, format!("@{}", digest)),
+        None => match image.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, format!(":{}", tag)),
+            _ => (image, ":latest".into()),
+        },
+    };
+    qualify_name(name) + &suffix
+}
+
+/// Prepend default registry and namespace to image name if omitted.
+fn qualify_name(name: &str) -> String {
+    match name.split_once('/') {
+        // first component is a registry if it looks like a host name
+        Some((registry, _))
+            if registry.contains('.') || registry.contains(':') || registry == "localhost" =>
+        {
+            name.to_string()
+        }
+        Some(_) => format!("docker.io/{}", name),
+        None => format!("docker.io/library/{}", name),
+    }
+}
+
+/// Match text against pattern where `*` matches any sequence of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            text.starts_with(prefix)
+                && (prefix.len()..=text.len())
+                    .filter(|&i| text.is_char_boundary(i))
+                    .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+/// Host name with optional port, e.g. `pypi.org`, `*.github.com` or
+/// `localhost:8080`.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct NetDescriptor(pub String);
+
+impl NetDescriptor {
+    /// Split into host name and port.
+    pub fn split(&self) -> (&str, Option<&str>) {
+        split_host(&self.0)
+    }
+}
+
+/// Descriptors identifying a filesystem subtree.
+pub trait PathDescriptor {
+    fn path(&self) -> &Path;
+    fn from_path(path: PathBuf) -> Self;
+}
+
+impl PathDescriptor for ReadDescriptor {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+    fn from_path(path: PathBuf) -> Self {
+        ReadDescriptor(path)
+    }
+}
+
+impl PathDescriptor for WriteDescriptor {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+    fn from_path(path: PathBuf) -> Self {
+        WriteDescriptor(path)
+    }
+}
+
+/// What a permission grants, deciding whether access to a target is covered.
+pub trait Descriptor: Eq + Hash + Sized {
+    /// What is accessed, e.g. a path or a name.
+    type Target: ?Sized;
+    fn check(permission: &UnaryPermission<Self>, target: &Self::Target) -> Result<(), Error>;
+}
+
+impl<T: Descriptor> UnaryPermission<T> {
+    /// Check access to a target.
+    ///
+    /// For paths, this covers the path and everything beneath it. A denied
+    /// subtree always wins over a broader grant. Since granting a directory
+    /// also exposes its children, a path containing a denied subtree is
+    /// denied as well. The path is normalized lexically before checking, but
+    /// symlinks are not followed. Use [`UnaryPermission::check_resolved`] for
+    /// paths on disk.
+    pub fn check(&self, target: &T::Target) -> Result<(), Error> {
+        T::check(self, target)
+    }
+}
+
+impl Descriptor for ReadDescriptor {
+    type Target = Path;
+    fn check(permission: &UnaryPermission<Self>, path: &Path) -> Result<(), Error> {
+        permission.check_with_info(&normalize_path(path), None)
+    }
+}
+
+impl Descriptor for WriteDescriptor {
+    type Target = Path;
+    fn check(permission: &UnaryPermission<Self>, path: &Path) -> Result<(), Error> {
+        permission.check_with_info(&normalize_path(path), None)
+    }
+}
+
+impl<T: PathDescriptor + Eq + Hash> UnaryPermission<T> {
+    /// Check access to a path on disk and return the path with all symlinks
+    /// resolved, which is what should actually be accessed.
+    ///
+    /// Both the given path and its resolved target must be allowed, so that a
+    /// symlink inside an allowed directory cannot escape to elsewhere.
+    pub fn check_resolved(&self, path: &Path) -> Result<PathBuf, Error> {
+        let path = normalize_path(&std::env::current_dir()?.join(path));
+        self.check_with_info(&path, None)?;
+        let resolved = path.canonicalize()?;
+        if resolved != path {
+            log::debug!("path {:?} resolved to {:?}", path, resolved);
+            self.check_with_info(
+                &resolved,
+                Some(&format!(
+                    "{} (resolved from {})",
+                    resolved.display(),
+                    path.display()
+                )),
+            )?;
+        }
+        Ok(resolved)
+    }
+
+    /// Like [`UnaryPermission::check_resolved`], but prompt for access if it
+    /// is not granted. Explicitly denied paths are never prompted for.
+    pub fn request_resolved(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        let result = self.check_resolved(path);
+        if result.is_ok() || !self.prompt {
+            return result;
+        }
+        let path = normalize_path(&std::env::current_dir()?.join(path));
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => return result,
+        };
+        if self.denied_by(&path).is_some() || self.denied_by(&resolved).is_some() {
+            return result;
+        }
+        let info = resolved.display().to_string();
+        if self.ask(
+            &info,
+            vec![T::from_path(path.clone()), T::from_path(resolved)],
+        ) {
+            self.check_resolved(&path)
+        } else {
+            result
+        }
+    }
+
+    fn denied_by(&self, path: &Path) -> Option<&T> {
+        self.denied_list.iter().find(|desc| {
+            let denied = normalize_path(desc.path());
+            path.starts_with(&denied) || denied.starts_with(path)
+        })
+    }
+
+    fn check_with_info(&self, path: &Path, info: Option<&str>) -> Result<(), Error> {
+        let info = info.or_else(|| path.to_str());
+        if let Some(denied) = self.denied_by(path) {
+            PermissionState::explicitly_denied(self.name, info, denied.path())
+        } else if self.global_state == PermissionState::Granted
+            || self
+                .granted_list
                 .iter()
-                .any(|path_| path_.0.starts_with(path))
-            {
-                PermissionState::Denied.check(self.name, path.to_str())
-            } else {
-                PermissionState::Granted.check(self.name, path.to_str())
-            }
-        } else if self
-            .granted_list
-            .iter()
-            .any(|path_| path.starts_with(&path_.0))
+                .any(|desc| path.starts_with(normalize_path(desc.path())))
         {
-            PermissionState::Granted.check(self.name, path.to_str())
+            PermissionState::Granted.check(self.name, info)
         } else {
-            PermissionState::Denied.check(self.name, path.to_str())
+            PermissionState::Denied.check(self.name, info)
         }
     }
 }
 
-impl UnaryPermission<WriteDescriptor> {
-    pub fn check(&self, path: &Path) -> Result<(), Error> {
-        if self.global_state == PermissionState::Granted {
-            if self
-                .denied_list
+/// Resolved path of `source` for audit records, which is also recorded for
+/// denied paths if they exist.
+fn resolved_display<E>(source: &Path, result: Result<&PathBuf, E>) -> Option<String> {
+    let resolved = match result {
+        Ok(path) => Some(path.clone()),
+        Err(_) => std::env::current_dir()
+            .ok()
+            .and_then(|dir| normalize_path(&dir.join(source)).canonicalize().ok()),
+    };
+    resolved.map(|path| path.display().to_string())
+}
+
+/// Normalize a path lexically, removing `.` and resolving `..` components
+/// without touching the filesystem.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                // `/..` is still `/`
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn split_host(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => (name, Some(port)),
+        _ => (host, None),
+    }
+}
+
+/// Descriptors identifying something by name, e.g. a host, an environment
+/// variable, a volume or an image.
+pub trait NameDescriptor {
+    /// Whether this grants access to the named item.
+    fn matches(&self, name: &str) -> bool;
+    /// Descriptor granting access to exactly the named item.
+    fn from_name(name: &str) -> Self;
+}
+
+impl NameDescriptor for NetDescriptor {
+    fn matches(&self, host: &str) -> bool {
+        let (name, port) = self.split();
+        let (host_name, host_port) = split_host(host);
+        let name_matches = match name.strip_prefix("*.") {
+            Some(domain) => host_name.ends_with(&format!(".{}", domain)),
+            None => host_name == name,
+        };
+        name_matches && (port.is_none() || port == host_port)
+    }
+    fn from_name(host: &str) -> Self {
+        NetDescriptor(host.into())
+    }
+}
+
+impl NameDescriptor for EnvDescriptor {
+    fn matches(&self, name: &str) -> bool {
+        self.0 == name
+    }
+    fn from_name(name: &str) -> Self {
+        EnvDescriptor(name.into())
+    }
+}
+
+impl NameDescriptor for VolumeDescriptor {
+    fn matches(&self, name: &str) -> bool {
+        self.0 == name
+    }
+    fn from_name(name: &str) -> Self {
+        VolumeDescriptor(name.into())
+    }
+}
+
+/// Named items, e.g. a host (with optional port), a host environment
+/// variable, a docker volume or a container image, are checked one at a time.
+impl<T: NameDescriptor + Eq + Hash> Descriptor for T {
+    type Target = str;
+    fn check(permission: &UnaryPermission<Self>, name: &str) -> Result<(), Error> {
+        if permission.global_state == PermissionState::Granted
+            || permission
+                .granted_list
                 .iter()
-                .any(|path_| path_.0.starts_with(path))
-            {
-                PermissionState::Denied.check(self.name, path.to_str())
-            } else {
-                PermissionState::Granted.check(self.name, path.to_str())
-            }
-        } else if self
-            .granted_list
-            .iter()
-            .any(|path_| path.starts_with(&path_.0))
+                .any(|desc| desc.matches(name))
         {
-            PermissionState::Granted.check(self.name, path.to_str())
+            PermissionState::Granted.check(permission.name, Some(name))
         } else {
-            PermissionState::Denied.check(self.name, path.to_str())
+            PermissionState::Denied.check(permission.name, Some(name))
         }
     }
 }
 
+impl<T: NameDescriptor + Eq + Hash> UnaryPermission<T> {
+    /// Like [`UnaryPermission::check`], but prompt for access if it is not
+    /// granted.
+    pub fn request(&mut self, name: &str) -> Result<(), Error> {
+        let result = self.check(name);
+        if result.is_err() && self.ask(name, vec![T::from_name(name)]) {
+            return Ok(());
+        }
+        result
+    }
+}
+
+impl UnaryPermission<NetDescriptor> {
+    /// Check unrestricted network access.
+    pub fn check_all(&self) -> Result<(), Error> {
+        self.global_state.check(self.name, None)
+    }
+}
+
 impl Default for UnaryPermission<ReadDescriptor> {
     fn default() -> Self {
         UnaryPermission::<ReadDescriptor> {
@@ -125,6 +465,7 @@ impl Default for UnaryPermission<ReadDescriptor> {
             global_state: Default::default(),
             granted_list: Default::default(),
             denied_list: Default::default(),
+            prompt: false,
         }
     }
 }
@@ -136,6 +477,55 @@ impl Default for UnaryPermission<WriteDescriptor> {
             global_state: Default::default(),
             granted_list: Default::default(),
             denied_list: Default::default(),
+            prompt: false,
+        }
+    }
+}
+
+impl Default for UnaryPermission<NetDescriptor> {
+    fn default() -> Self {
+        UnaryPermission::<NetDescriptor> {
+            name: "net",
+            global_state: Default::default(),
+            granted_list: Default::default(),
+            denied_list: Default::default(),
+            prompt: false,
+        }
+    }
+}
+
+impl Default for UnaryPermission<EnvDescriptor> {
+    fn default() -> Self {
+        UnaryPermission::<EnvDescriptor> {
+            name: "env",
+            global_state: Default::default(),
+            granted_list: Default::default(),
+            denied_list: Default::default(),
+            prompt: false,
+        }
+    }
+}
+
+impl Default for UnaryPermission<VolumeDescriptor> {
+    fn default() -> Self {
+        UnaryPermission::<VolumeDescriptor> {
+            name: "volume",
+            global_state: Default::default(),
+            granted_list: Default::default(),
+            denied_list: Default::default(),
+            prompt: false,
+        }
+    }
+}
+
+impl Default for UnaryPermission<ImageDescriptor> {
+    fn default() -> Self {
+        UnaryPermission::<ImageDescriptor> {
+            name: "image",
+            global_state: Default::default(),
+            granted_list: Default::default(),
+            denied_list: Default::default(),
+            prompt: false,
         }
     }
 }
@@ -145,7 +535,18 @@ impl Default for UnaryPermission<WriteDescriptor> {
 pub struct Permissions {
     pub read: UnaryPermission<ReadDescriptor>,
     pub write: UnaryPermission<WriteDescriptor>,
-    pub net: UnitPermission,
+    pub net: UnaryPermission<NetDescriptor>,
+    /// Network access while building images from `extend`. Unlike the
+    /// default permissions, options (and thus the CLI) deny it unless
+    /// granted, e.g. with `--allow-build-net`.
+    pub build_net: UnaryPermission<NetDescriptor>,
+    pub env: UnaryPermission<EnvDescriptor>,
+    pub volume: UnaryPermission<VolumeDescriptor>,
+    pub image: UnaryPermission<ImageDescriptor>,
+    pub security: SecurityProfile,
+    pub resources: ResourceLimits,
+    /// Decisions made so far.
+    pub audit: Vec<AuditRecord>,
 }
 
 impl Default for Permissions {
@@ -159,10 +560,24 @@ impl Default for Permissions {
                 global_state: PermissionState::Denied,
                 ..Default::default()
             },
-            net: UnitPermission {
-                name: "net",
-                state: PermissionState::Granted,
+            net: UnaryPermission {
+                global_state: PermissionState::Granted,
+                ..Default::default()
             },
+            build_net: UnaryPermission {
+                name: "build-net",
+                global_state: PermissionState::Granted,
+                ..Default::default()
+            },
+            env: Default::default(),
+            volume: Default::default(),
+            image: UnaryPermission {
+                global_state: PermissionState::Granted,
+                ..Default::default()
+            },
+            security: Default::default(),
+            resources: Default::default(),
+            audit: vec![],
         }
     }
 }
@@ -173,25 +588,280 @@ impl Permissions {
             read: UnaryPermission {
                 global_state: global_state_from_option(&opts.allow_read),
                 granted_list: resolve_read_allowlist(&opts.allow_read),
+                denied_list: opts
+                    .deny_read
+                    .iter()
+                    .map(|path| ReadDescriptor(path.clone()))
+                    .collect(),
+                prompt: opts.prompt,
                 ..Default::default()
             },
             write: UnaryPermission {
                 global_state: global_state_from_option(&opts.allow_write),
                 granted_list: resolve_write_allowlist(&opts.allow_write),
+                denied_list: opts
+                    .deny_write
+                    .iter()
+                    .map(|path| WriteDescriptor(path.clone()))
+                    .collect(),
+                prompt: opts.prompt,
                 ..Default::default()
             },
-            net: UnitPermission {
-                name: "net",
-                state: if opts.allow_net {
-                    PermissionState::Granted
-                } else {
+            net: UnaryPermission {
+                global_state: global_state_from_option(&opts.allow_net),
+                granted_list: resolve_net_allowlist(&opts.allow_net),
+                prompt: opts.prompt,
+                ..Default::default()
+            },
+            build_net: UnaryPermission {
+                name: "build-net",
+                global_state: global_state_from_option(&opts.allow_build_net),
+                granted_list: resolve_net_allowlist(&opts.allow_build_net),
+                prompt: opts.prompt,
+                ..Default::default()
+            },
+            env: UnaryPermission {
+                global_state: global_state_from_option(&opts.allow_env),
+                granted_list: resolve_env_allowlist(&opts.allow_env),
+                prompt: opts.prompt,
+                ..Default::default()
+            },
+            volume: UnaryPermission {
+                global_state: global_state_from_option(&opts.allow_volume),
+                granted_list: resolve_volume_allowlist(&opts.allow_volume),
+                prompt: opts.prompt,
+                ..Default::default()
+            },
+            image: UnaryPermission {
+                // any image is allowed unless an allowlist is given
+                global_state: if matches!(opts.allow_image, Some(ref v) if !v.is_empty()) {
                     PermissionState::Denied
+                } else {
+                    PermissionState::Granted
+                },
+                granted_list: resolve_image_allowlist(&opts.allow_image),
+                prompt: opts.prompt,
+                ..Default::default()
+            },
+            security: opts.security.clone(),
+            resources: opts.resources.clone(),
+            audit: vec![],
+        }
+    }
+
+    pub fn from_policy(policy: &Policy) -> Self {
+        Self::from_options(&policy.to_options())
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        log::debug!("audit: {:?}", event);
+        self.audit.push(AuditRecord::new(event));
+    }
+
+    /// Take audit records of decisions made so far.
+    pub fn take_audit(&mut self) -> Vec<AuditRecord> {
+        std::mem::take(&mut self.audit)
+    }
+
+    /// Decide how a host path is mounted into container. Unless `read_only`
+    /// is given, it is writable if write access is granted, otherwise
+    /// read-only. Returns the resolved path and whether it is read-only.
+    pub fn mount(
+        &mut self,
+        source: &Path,
+        target: &str,
+        read_only: Option<bool>,
+    ) -> Result<(PathBuf, bool), Error> {
+        let (result, access) = match read_only {
+            Some(true) => match self.read.request_resolved(source) {
+                Ok(path) => (Ok((path, true)), MountAccess::ReadOnly),
+                Err(e) => (Err(e), MountAccess::Denied),
+            },
+            Some(false) => match self.write.request_resolved(source) {
+                Ok(path) => (Ok((path, false)), MountAccess::ReadWrite),
+                Err(e) => (Err(e), MountAccess::Denied),
+            },
+            None => match self.write.check_resolved(source) {
+                Ok(path) => (Ok((path, false)), MountAccess::ReadWrite),
+                Err(_) => match self.read.request_resolved(source) {
+                    Ok(path) => (Ok((path, true)), MountAccess::ReadOnly),
+                    Err(e) => (Err(e), MountAccess::Denied),
                 },
             },
+        };
+        self.record(AuditEvent::Mount {
+            source: source.display().to_string(),
+            resolved: resolved_display(source, result.as_ref().map(|(path, _)| path)),
+            target: target.into(),
+            access,
+        });
+        result
+    }
+
+    /// Decide how a docker volume is mounted into container. It is writable
+    /// unless `read_only` is given. Returns whether it is read-only.
+    pub fn mount_volume(
+        &mut self,
+        name: &str,
+        target: &str,
+        read_only: Option<bool>,
+    ) -> Result<bool, Error> {
+        let read_only = read_only.unwrap_or(false);
+        let result = self.volume.request(name);
+        self.record(AuditEvent::Volume {
+            name: name.into(),
+            target: target.into(),
+            access: match (&result, read_only) {
+                (Err(_), _) => MountAccess::Denied,
+                (Ok(()), true) => MountAccess::ReadOnly,
+                (Ok(()), false) => MountAccess::ReadWrite,
+            },
+        });
+        result.map(|_| read_only)
+    }
+
+    /// Check whether a local file or directory can be used as asset, and
+    /// return its resolved path.
+    pub fn read_asset(&mut self, source: &Path) -> Result<PathBuf, Error> {
+        let result = self.read.request_resolved(source);
+        self.record(AuditEvent::Asset {
+            source: source.display().to_string(),
+            resolved: resolved_display(source, result.as_ref()),
+            granted: result.is_ok(),
+        });
+        result
+    }
+
+    /// Check whether a local file can be read as secret, and return its
+    /// resolved path.
+    pub fn read_secret(&mut self, name: &str, source: &Path) -> Result<PathBuf, Error> {
+        let result = self.read.request_resolved(source);
+        self.record(AuditEvent::Secret {
+            name: name.into(),
+            source: source.display().to_string(),
+            resolved: resolved_display(source, result.as_ref()),
+            granted: result.is_ok(),
+        });
+        result
+    }
+
+    /// Decide network access for building or running.
+    pub fn network(&mut self, phase: NetworkPhase) -> NetworkAccess {
+        let perm = match phase {
+            NetworkPhase::Build => &self.build_net,
+            NetworkPhase::Run => &self.net,
+        };
+        let access = if perm.check_all().is_ok() {
+            NetworkAccess::Enabled
+        } else if perm.granted_list.is_empty() {
+            NetworkAccess::Disabled
+        } else {
+            let mut hosts = perm
+                .granted_list
+                .iter()
+                .map(|desc| desc.0.clone())
+                .collect::<Vec<_>>();
+            hosts.sort();
+            NetworkAccess::Filtered(hosts)
+        };
+        self.record(AuditEvent::Network {
+            phase,
+            access: access.clone(),
+        });
+        access
+    }
+
+    /// Check whether a host environment variable can be forwarded.
+    pub fn forward_env(&mut self, name: &str) -> Result<(), Error> {
+        let result = self.env.request(name);
+        self.record(AuditEvent::Env {
+            name: name.into(),
+            granted: result.is_ok(),
+        });
+        result
+    }
+
+    /// Check whether a container image can be used.
+    pub fn use_image(&mut self, image: &str) -> Result<(), Error> {
+        let result = self.image.request(image);
+        self.record(AuditEvent::Image {
+            image: image.into(),
+            granted: result.is_ok(),
+        });
+        result
+    }
+
+    /// Check all permissions required by a task at once, prompting for the
+    /// missing ones if enabled. All missing grants are reported in one error.
+    pub fn check_requirements(&mut self, req: &PermissionRequirements) -> Result<(), Error> {
+        let mut results = vec![];
+        for path in req.read.iter() {
+            let result = request_path(&mut self.read, path);
+            results.push(("read", path.display().to_string(), result));
+        }
+        for path in req.write.iter() {
+            let result = request_path(&mut self.write, path);
+            results.push(("write", path.display().to_string(), result));
+        }
+        for host in req.net.iter() {
+            results.push(("net", host.clone(), self.net.request(host)));
+        }
+        for name in req.env.iter() {
+            results.push(("env", name.clone(), self.env.request(name)));
+        }
+        for name in req.volume.iter() {
+            results.push(("volume", name.clone(), self.volume.request(name)));
+        }
+
+        let mut missing = vec![];
+        for (permission, target, result) in results {
+            self.record(AuditEvent::Requirement {
+                permission: permission.into(),
+                target,
+                granted: result.is_ok(),
+            });
+            match result {
+                Ok(()) => {}
+                Err(Error::PermissionDeniedError(e)) => missing.push(e),
+                Err(e) => return Err(e),
+            }
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PermissionDeniedError(missing.join("; ")))
         }
     }
 }
 
+/// Check a required path, following symlinks only if it already exists.
+fn request_path<T: PathDescriptor + Eq + Hash>(
+    perm: &mut UnaryPermission<T>,
+    path: &Path,
+) -> Result<(), Error> {
+    if path.exists() {
+        perm.request_resolved(path).map(|_| ())
+    } else {
+        perm.check_with_info(&normalize_path(&std::env::current_dir()?.join(path)), None)
+    }
+}
+
+/// Permissions a task declares it needs.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PermissionRequirements {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub read: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub write: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub net: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub volume: Vec<String>,
+}
+
 fn global_state_from_option<T>(flag: &Option<Vec<T>>) -> PermissionState {
     if matches!(flag, Some(v) if v.is_empty()) {
         PermissionState::Granted
@@ -220,11 +890,87 @@ pub fn resolve_write_allowlist(allow: &Option<Vec<PathBuf>>) -> HashSet<WriteDes
     }
 }
 
+pub fn resolve_net_allowlist(allow: &Option<Vec<String>>) -> HashSet<NetDescriptor> {
+    if let Some(v) = allow {
+        v.iter().map(|host| NetDescriptor(host.clone())).collect()
+    } else {
+        HashSet::new()
+    }
+}
+
+pub fn resolve_env_allowlist(allow: &Option<Vec<String>>) -> HashSet<EnvDescriptor> {
+    if let Some(v) = allow {
+        v.iter().map(|name| EnvDescriptor(name.clone())).collect()
+    } else {
+        HashSet::new()
+    }
+}
+
+pub fn resolve_volume_allowlist(allow: &Option<Vec<String>>) -> HashSet<VolumeDescriptor> {
+    if let Some(v) = allow {
+        v.iter()
+            .map(|name| VolumeDescriptor(name.clone()))
+            .collect()
+    } else {
+        HashSet::new()
+    }
+}
+
+pub fn resolve_image_allowlist(allow: &Option<Vec<String>>) -> HashSet<ImageDescriptor> {
+    if let Some(v) = allow {
+        v.iter()
+            .map(|pattern| ImageDescriptor(pattern.clone()))
+            .collect()
+    } else {
+        HashSet::new()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct PermissionsOptions {
     pub allow_read: Option<Vec<PathBuf>>,
     pub allow_write: Option<Vec<PathBuf>>,
-    pub allow_net: bool,
+    pub deny_read: Vec<PathBuf>,
+    pub deny_write: Vec<PathBuf>,
+    pub allow_net: Option<Vec<String>>,
+    pub allow_build_net: Option<Vec<String>>,
+    pub allow_env: Option<Vec<String>>,
+    /// Docker volumes allowed to be mounted.
+    pub allow_volume: Option<Vec<String>>,
+    /// Image patterns allowed; any image is allowed when not given.
+    pub allow_image: Option<Vec<String>>,
+    pub security: SecurityProfile,
+    pub resources: ResourceLimits,
+    /// Prompt on the terminal for permissions not granted.
+    pub prompt: bool,
+}
+
+impl PermissionsOptions {
+    /// Extend allowed and denied lists with the ones in `other`, keeping
+    /// other settings of `self`.
+    pub fn merge(mut self, other: &PermissionsOptions) -> Self {
+        merge_allowlist(&mut self.allow_read, &other.allow_read);
+        merge_allowlist(&mut self.allow_write, &other.allow_write);
+        merge_allowlist(&mut self.allow_net, &other.allow_net);
+        merge_allowlist(&mut self.allow_build_net, &other.allow_build_net);
+        merge_allowlist(&mut self.allow_env, &other.allow_env);
+        merge_allowlist(&mut self.allow_volume, &other.allow_volume);
+        merge_allowlist(&mut self.allow_image, &other.allow_image);
+        self.deny_read.extend(other.deny_read.iter().cloned());
+        self.deny_write.extend(other.deny_write.iter().cloned());
+        self
+    }
+}
+
+fn merge_allowlist<T: Clone>(this: &mut Option<Vec<T>>, other: &Option<Vec<T>>) {
+    match (this.as_mut(), other) {
+        (_, None) => {}
+        (None, Some(other)) => *this = Some(other.clone()),
+        // empty list grants everything
+        (Some(this), Some(_)) if this.is_empty() => {}
+        (Some(this), Some(other)) if other.is_empty() => this.clear(),
+        (Some(this), Some(other)) => this.extend(other.iter().cloned()),
+    }
 }
 
 #[cfg(test)]
@@ -292,8 +1038,369 @@ mod tests {
         assert!(perms.read.check(Path::new("/b/e")).is_err());
         assert!(perms.write.check(Path::new("/b/e")).is_err());
 
+        // Escaping /b/c, needs normalizing
+        assert!(perms.read.check(Path::new("/b/c/../e")).is_err());
+        assert!(perms.write.check(Path::new("/b/c/./../e")).is_err());
+        assert!(perms.read.check(Path::new("/b/c/../../b/c/d")).is_ok());
+
         // Inside of /a but outside of /a/specific
         assert!(perms.read.check(Path::new("/a/b")).is_err());
         assert!(perms.write.check(Path::new("/a/b")).is_err());
     }
+
+    #[test]
+    fn check_hosts() {
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_net: Some(vec![
+                "pypi.org".into(),
+                "*.github.com".into(),
+                "localhost:8080".into(),
+            ]),
+            ..Default::default()
+        });
+
+        assert!(perms.net.check_all().is_err());
+
+        // Exact match, with any port
+        assert!(perms.net.check("pypi.org").is_ok());
+        assert!(perms.net.check("pypi.org:443").is_ok());
+        assert!(perms.net.check("files.pypi.org").is_err());
+
+        // Wildcard subdomains
+        assert!(perms.net.check("api.github.com").is_ok());
+        assert!(perms.net.check("github.com").is_err());
+        assert!(perms.net.check("evilgithub.com").is_err());
+
+        // Port restricted
+        assert!(perms.net.check("localhost:8080").is_ok());
+        assert!(perms.net.check("localhost:22").is_err());
+        assert!(perms.net.check("localhost").is_err());
+
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_net: Some(vec![]),
+            ..Default::default()
+        });
+        assert!(perms.net.check_all().is_ok());
+        assert!(perms.net.check("example.com").is_ok());
+    }
+
+    #[test]
+    fn check_envs() {
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_env: Some(vec!["CI_TOKEN".into()]),
+            ..Default::default()
+        });
+        assert!(perms.env.check("CI_TOKEN").is_ok());
+        assert!(perms.env.check("HOME").is_err());
+
+        // Denied unless explicitly granted
+        let perms = Permissions::default();
+        assert!(perms.env.check("CI_TOKEN").is_err());
+    }
+
+    #[test]
+    fn escape_prompt() {
+        assert_eq!(escape_control("python:3"), "python:3");
+        assert_eq!(escape_control("evil\r\x1b[2KHOME"), "evil\\r\\u{1b}[2KHOME");
+        assert_eq!(escape_control("döcker\n"), "döcker\\n");
+    }
+
+    #[test]
+    fn decide_network_phases() {
+        let mut perms = Permissions::from_options(&PermissionsOptions {
+            allow_net: Some(vec![]),
+            allow_build_net: Some(vec!["pypi.org".into(), "*.pythonhosted.org".into()]),
+            ..Default::default()
+        });
+        assert_eq!(
+            perms.network(NetworkPhase::Build),
+            NetworkAccess::Filtered(vec!["*.pythonhosted.org".into(), "pypi.org".into()])
+        );
+        assert_eq!(perms.network(NetworkPhase::Run), NetworkAccess::Enabled);
+
+        let mut perms = Permissions::from_options(&PermissionsOptions {
+            allow_build_net: Some(vec![]),
+            ..Default::default()
+        });
+        assert_eq!(perms.network(NetworkPhase::Build), NetworkAccess::Enabled);
+        assert_eq!(perms.network(NetworkPhase::Run), NetworkAccess::Disabled);
+        let audit = perms.take_audit();
+        assert_eq!(
+            audit[1].event,
+            AuditEvent::Network {
+                phase: NetworkPhase::Run,
+                access: NetworkAccess::Disabled
+            }
+        );
+
+        // Denied for both unless explicitly granted
+        let mut perms = Permissions::from_options(&Default::default());
+        assert_eq!(perms.network(NetworkPhase::Build), NetworkAccess::Disabled);
+        assert_eq!(perms.network(NetworkPhase::Run), NetworkAccess::Disabled);
+    }
+
+    #[test]
+    fn check_volumes() {
+        let mut perms = Permissions::from_options(&PermissionsOptions {
+            allow_volume: Some(vec!["cache".into()]),
+            ..Default::default()
+        });
+        assert!(!perms.mount_volume("cache", "/cache", None).unwrap());
+        assert!(perms.mount_volume("cache", "/cache", Some(true)).unwrap());
+        assert!(perms.mount_volume("data", "/data", None).is_err());
+        let audit = perms.take_audit();
+        assert_eq!(
+            audit[0].event,
+            AuditEvent::Volume {
+                name: "cache".into(),
+                target: "/cache".into(),
+                access: MountAccess::ReadWrite
+            }
+        );
+        assert_eq!(
+            audit[2].event,
+            AuditEvent::Volume {
+                name: "data".into(),
+                target: "/data".into(),
+                access: MountAccess::Denied
+            }
+        );
+
+        // Denied unless explicitly granted
+        let perms = Permissions::default();
+        assert!(perms.volume.check("cache").is_err());
+    }
+
+    #[test]
+    fn check_images() {
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_image: Some(vec![
+                "python:*".into(),
+                "rust:1.*".into(),
+                "ghcr.io/org/*".into(),
+                "alpine@sha256:1234".into(),
+            ]),
+            ..Default::default()
+        });
+
+        // Tag patterns
+        assert!(perms.image.check("python:3").is_ok());
+        assert!(perms.image.check("docker.io/library/python:3.9").is_ok());
+        assert!(perms.image.check("rust:1.56").is_ok());
+        assert!(perms.image.check("rust:latest").is_err());
+        assert!(perms.image.check("rust").is_err());
+
+        // Registry prefix
+        assert!(perms.image.check("ghcr.io/org/grader:v1").is_ok());
+        assert!(perms.image.check("ghcr.io/other/grader:v1").is_err());
+
+        // Digest pinning
+        assert!(perms.image.check("alpine@sha256:1234").is_ok());
+        assert!(perms.image.check("library/alpine@sha256:1234").is_ok());
+        assert!(perms.image.check("alpine@sha256:5678").is_err());
+        assert!(perms.image.check("alpine:3").is_err());
+
+        // Any image when no allowlist is given
+        let perms = Permissions::from_options(&Default::default());
+        assert!(perms.image.check("ubuntu").is_ok());
+    }
+
+    #[test]
+    fn check_denied_paths() {
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_read: Some(vec![PathBuf::from("/a")]),
+            allow_write: Some(vec![]),
+            deny_read: vec![PathBuf::from("/a/secret")],
+            deny_write: vec![PathBuf::from("/etc")],
+            ..Default::default()
+        });
+
+        // Denied subtree beats a broader grant
+        assert!(perms.read.check(Path::new("/a/public")).is_ok());
+        assert!(perms.read.check(Path::new("/a/secret")).is_err());
+        assert!(perms.read.check(Path::new("/a/secret/key")).is_err());
+
+        // Granting a parent would expose the denied subtree
+        assert!(perms.read.check(Path::new("/a")).is_err());
+
+        // Denied subtree beats global grant
+        assert!(perms.write.check(Path::new("/tmp")).is_ok());
+        assert!(perms.write.check(Path::new("/etc/passwd")).is_err());
+        assert!(perms.write.check(Path::new("/")).is_err());
+
+        // Only a prefix in name, not in path
+        assert!(perms.read.check(Path::new("/a/secrets")).is_ok());
+    }
+
+    #[test]
+    fn normalize_paths() {
+        assert_eq!(normalize_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize_path(Path::new("/../a")), Path::new("/a"));
+        assert_eq!(normalize_path(Path::new("a/../../b")), Path::new("../b"));
+        assert_eq!(normalize_path(Path::new("../../a")), Path::new("../../a"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let allowed = root.join("allowed");
+        let outside = root.join("outside");
+        std::fs::create_dir_all(allowed.join("dir")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, allowed.join("escape")).unwrap();
+        std::os::unix::fs::symlink(allowed.join("dir"), allowed.join("inner")).unwrap();
+
+        let perms = Permissions::from_options(&PermissionsOptions {
+            allow_read: Some(vec![allowed.clone()]),
+            ..Default::default()
+        });
+
+        // Symlink within allowed directory
+        assert_eq!(
+            perms.read.check_resolved(&allowed.join("inner")).unwrap(),
+            allowed.join("dir")
+        );
+
+        // Symlink escaping allowed directory, reported with resolved path
+        let err = perms
+            .read
+            .check_resolved(&allowed.join("escape"))
+            .unwrap_err();
+        assert!(err.to_string().contains(outside.to_str().unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn audit_resolved_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let allowed = root.join("allowed");
+        let outside = root.join("outside");
+        std::fs::create_dir_all(allowed.join("dir")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(allowed.join("dir"), allowed.join("inner")).unwrap();
+        std::os::unix::fs::symlink(&outside, allowed.join("escape")).unwrap();
+
+        let mut perms = Permissions::from_options(&PermissionsOptions {
+            allow_read: Some(vec![allowed.clone()]),
+            ..Default::default()
+        });
+        perms.read_asset(&allowed.join("inner")).unwrap();
+        perms.read_asset(&allowed.join("escape")).unwrap_err();
+        perms
+            .mount(&allowed.join("./inner"), "/data", None)
+            .unwrap();
+
+        let audit = perms.take_audit();
+        assert_eq!(
+            audit[0].event,
+            AuditEvent::Asset {
+                source: allowed.join("inner").display().to_string(),
+                resolved: Some(allowed.join("dir").display().to_string()),
+                granted: true
+            }
+        );
+        // Denied paths are recorded with where they actually point to
+        assert_eq!(
+            audit[1].event,
+            AuditEvent::Asset {
+                source: allowed.join("escape").display().to_string(),
+                resolved: Some(outside.display().to_string()),
+                granted: false
+            }
+        );
+        assert_eq!(
+            audit[2].event,
+            AuditEvent::Mount {
+                source: allowed.join("./inner").display().to_string(),
+                resolved: Some(allowed.join("dir").display().to_string()),
+                target: "/data".into(),
+                access: MountAccess::ReadOnly
+            }
+        );
+    }
+
+    #[test]
+    fn merge_options() {
+        let policy = Policy::from_yaml(
+            "
+read:
+  allow: [/a]
+  deny: [/a/secret]
+net:
+  allow: []
+image:
+  allow: [\"python:*\"]
+resource:
+  memory: 512m
+",
+        )
+        .unwrap();
+        let opts = policy.to_options().merge(&PermissionsOptions {
+            allow_read: Some(vec![PathBuf::from("/b")]),
+            allow_net: Some(vec!["pypi.org".into()]),
+            allow_env: Some(vec!["CI_TOKEN".into()]),
+            ..Default::default()
+        });
+        assert_eq!(opts.resources.memory, 512 << 20);
+
+        let perms = Permissions::from_options(&opts);
+        assert!(perms.read.check(Path::new("/a/public")).is_ok());
+        assert!(perms.read.check(Path::new("/a/secret")).is_err());
+        assert!(perms.read.check(Path::new("/b")).is_ok());
+        assert!(perms.net.check_all().is_ok());
+        assert!(perms.env.check("CI_TOKEN").is_ok());
+        assert!(perms.image.check("python:3").is_ok());
+        assert!(perms.image.check("rust").is_err());
+    }
+
+    #[test]
+    fn check_requirements() {
+        let mut perms = Permissions::from_options(&PermissionsOptions {
+            allow_read: Some(vec![PathBuf::from("/a")]),
+            allow_net: Some(vec!["pypi.org".into()]),
+            ..Default::default()
+        });
+
+        assert!(perms
+            .check_requirements(&PermissionRequirements {
+                read: vec![PathBuf::from("/a/data")],
+                net: vec!["pypi.org".into()],
+                ..Default::default()
+            })
+            .is_ok());
+
+        // All missing grants are reported together
+        let err = perms
+            .check_requirements(&PermissionRequirements {
+                read: vec![PathBuf::from("/a/data"), PathBuf::from("/b/data")],
+                write: vec![PathBuf::from("/a/out")],
+                net: vec!["github.com".into()],
+                env: vec!["CI_TOKEN".into()],
+                volume: vec!["cache".into()],
+            })
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains("/a/data"));
+        assert!(err.contains("read access to /b/data"));
+        assert!(err.contains("write access to /a/out"));
+        assert!(err.contains("net access to github.com"));
+        assert!(err.contains("env access to CI_TOKEN"));
+        assert!(err.contains("volume access to cache"));
+
+        // Every decision is recorded
+        let audit = perms.take_audit();
+        assert_eq!(audit.len(), 8);
+        assert_eq!(
+            audit[3].event,
+            AuditEvent::Requirement {
+                permission: "read".into(),
+                target: "/b/data".into(),
+                granted: false
+            }
+        );
+        assert!(perms.take_audit().is_empty());
+    }
 }
@@ -0,0 +1,136 @@
+//! Permission policy files.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{permission::normalize_path, size::ByteSize, Error, PermissionsOptions};
+
+/// Permission policy, usually loaded from a YAML or TOML file.
+///
+/// ```yaml
+/// read:
+///   allow: [./examples]
+///   deny: [./examples/secret]
+/// write:
+///   allow: []          # empty list grants access to everything
+/// net:
+///   allow: [pypi.org, files.pythonhosted.org]
+/// env:
+///   allow: [CI_TOKEN]
+/// volume:
+///   allow: [pip-cache]
+/// image:
+///   allow: ["python:*"]
+/// resource:
+///   cpus: 0.5
+///   memory: 512m
+///   pids: 128
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub read: PathRule,
+    pub write: PathRule,
+    pub net: ListRule,
+    pub build_net: ListRule,
+    pub env: ListRule,
+    pub volume: ListRule,
+    pub image: ListRule,
+    pub resource: ResourceRule,
+}
+
+/// Allowed and denied paths.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathRule {
+    pub allow: Option<Vec<PathBuf>>,
+    pub deny: Vec<PathBuf>,
+}
+
+/// Allowed items, like hosts or image patterns.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListRule {
+    pub allow: Option<Vec<String>>,
+}
+
+/// Resource limits, defaults are used for omitted ones.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResourceRule {
+    pub cpus: Option<f64>,
+    pub memory: Option<ByteSize>,
+    pub pids: Option<i64>,
+}
+
+impl Policy {
+    pub fn from_yaml(s: &str) -> Result<Policy, Error> {
+        serde_yaml::from_str(s).map_err(|e| Error::SpecError(e.to_string()))
+    }
+
+    pub fn from_toml(s: &str) -> Result<Policy, Error> {
+        toml::from_str(s).map_err(|e| Error::SpecError(e.to_string()))
+    }
+
+    /// Load policy from a `.yaml`, `.yml` or `.toml` file. Relative paths in
+    /// the policy are resolved against the directory of the file.
+    pub fn from_file(path: &Path) -> Result<Policy, Error> {
+        let s = std::fs::read_to_string(path)?;
+        let policy = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&s)?,
+            Some("yaml") | Some("yml") => Self::from_yaml(&s)?,
+            _ => {
+                return Err(Error::SpecError(format!(
+                    "unknown policy file format: {}",
+                    path.display()
+                )))
+            }
+        };
+        let base = std::env::current_dir()?.join(path);
+        Ok(policy.relative_to(base.parent().expect("file should have parent")))
+    }
+
+    /// Resolve relative paths against `base`.
+    pub fn relative_to(mut self, base: &Path) -> Policy {
+        let resolve = |paths: &mut Vec<PathBuf>| {
+            for path in paths.iter_mut() {
+                let absolute = normalize_path(&base.join(&path));
+                // match how paths given in command line are canonicalized
+                *path = absolute.canonicalize().unwrap_or(absolute);
+            }
+        };
+        for rule in [&mut self.read, &mut self.write] {
+            if let Some(allow) = &mut rule.allow {
+                resolve(allow);
+            }
+            resolve(&mut rule.deny);
+        }
+        self
+    }
+
+    pub fn to_options(&self) -> PermissionsOptions {
+        let mut opts = PermissionsOptions {
+            allow_read: self.read.allow.clone(),
+            allow_write: self.write.allow.clone(),
+            deny_read: self.read.deny.clone(),
+            deny_write: self.write.deny.clone(),
+            allow_net: self.net.allow.clone(),
+            allow_build_net: self.build_net.allow.clone(),
+            allow_env: self.env.allow.clone(),
+            allow_volume: self.volume.allow.clone(),
+            allow_image: self.image.allow.clone(),
+            ..Default::default()
+        };
+        if let Some(cpus) = self.resource.cpus {
+            opts.resources.cpus = cpus;
+        }
+        if let Some(memory) = self.resource.memory {
+            opts.resources.memory = memory.0;
+        }
+        if let Some(pids) = self.resource.pids {
+            opts.security.pids_limit = Some(pids);
+        }
+        opts
+    }
+}
@@ -1,37 +1,590 @@
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    future::poll_fn,
+    lock::Mutex,
+};
+use serde::Serialize;
 
-use crate::Error;
+use crate::{
+    audit::AuditRecord,
+    runner::{RunnerReporter, Status},
+    Error,
+};
+
+/// Where a reported event comes from.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ReportContext {
+    pub task_id: String,
+    /// Name of the running stage, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    /// Index of the running stage in the task, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage_index: Option<usize>,
+    /// Attempt of running the task, starting from 1.
+    pub attempt: u32,
+}
+
+impl ReportContext {
+    pub fn new(task_id: impl Into<String>) -> Self {
+        Self {
+            task_id: task_id.into(),
+            stage: None,
+            stage_index: None,
+            attempt: 1,
+        }
+    }
+
+    /// Context of the given stage in the same task.
+    pub fn stage(&self, index: usize, name: &str) -> Self {
+        Self {
+            stage: Some(name.into()),
+            stage_index: Some(index),
+            ..self.clone()
+        }
+    }
+}
 
 /// Reporting running status and logs
 pub trait Reporter {
-    fn emit_stdout(&self, line: &str) -> Result<(), Error> {
-        let (ts, line) = line.split_once(' ').expect("expect to timestamp");
-        let timestamp =
-            DateTime::parse_from_rfc3339(ts).expect("expect timestamp to be of RFC3339");
-        self.report_stdout(line.trim_end(), timestamp.into())
+    fn emit_stdout(&self, context: &ReportContext, line: &str) -> Result<(), Error> {
+        let (timestamp, line) = split_timestamp(line);
+        self.report_stdout(context, line, timestamp)
+    }
+    fn emit_stderr(&self, context: &ReportContext, line: &str) -> Result<(), Error> {
+        let (timestamp, line) = split_timestamp(line);
+        self.report_stderr(context, line, timestamp)
+    }
+    fn report_stdout(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    fn report_stderr(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+}
+
+/// Split a docker log line into its timestamp and content.
+pub(crate) fn split_timestamp(line: &str) -> (DateTime<Utc>, &str) {
+    let (ts, line) = line.split_once(' ').expect("expect to timestamp");
+    let timestamp = DateTime::parse_from_rfc3339(ts).expect("expect timestamp to be of RFC3339");
+    (timestamp.into(), line.trim_end())
+}
+
+/// Reporting running status and logs without blocking the runtime, for
+/// reporters sending events to websockets, databases, message queues, etc.
+///
+/// Runner waits for each report to finish, so a slow reporter slows down
+/// reading logs instead of buffering them without bound. Every
+/// [`RunnerReporter`] is also an `AsyncReporter`.
+#[async_trait]
+pub trait AsyncReporter: Send + Sync {
+    async fn stdout(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    async fn stderr(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    async fn status(
+        &self,
+        context: &ReportContext,
+        status: &Status,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    async fn audit(&self, _context: &ReportContext, _record: &AuditRecord) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: RunnerReporter + Send + Sync> AsyncReporter for T {
+    async fn stdout(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.report_stdout(context, line, timestamp)
+    }
+    async fn stderr(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.report_stderr(context, line, timestamp)
+    }
+    async fn status(
+        &self,
+        context: &ReportContext,
+        status: &Status,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.report_status(context, status, timestamp)
     }
-    fn emit_stderr(&self, line: &str) -> Result<(), Error> {
-        let (ts, line) = line.split_once(' ').expect("expect to timestamp");
-        let timestamp =
-            DateTime::parse_from_rfc3339(ts).expect("expect timestamp to be of RFC3339");
-        self.report_stderr(line.trim_end(), timestamp.into())
+    async fn audit(&self, context: &ReportContext, record: &AuditRecord) -> Result<(), Error> {
+        self.report_audit(context, record)
     }
-    fn emit_console(&self, line: &str) -> Result<(), Error> {
-        self.report_stdout(line.trim_end(), Utc::now())
+}
+
+/// Event sent by [`ChannelReporter`].
+#[derive(Clone, Debug)]
+pub enum ReportEvent {
+    Stdout {
+        context: ReportContext,
+        line: String,
+        timestamp: DateTime<Utc>,
+    },
+    Stderr {
+        context: ReportContext,
+        line: String,
+        timestamp: DateTime<Utc>,
+    },
+    Status {
+        context: ReportContext,
+        status: Status,
+        timestamp: DateTime<Utc>,
+    },
+    Audit {
+        context: ReportContext,
+        record: AuditRecord,
+    },
+}
+
+/// Reporter sending events to a bounded channel, to be consumed elsewhere.
+///
+/// When the channel is full, reporting waits until the receiver catches up.
+/// Reporting fails once the receiver is dropped.
+pub struct ChannelReporter {
+    sender: Mutex<Sender<ReportEvent>>,
+}
+
+impl ChannelReporter {
+    /// Create a reporter and the receiving end of its channel, buffering at
+    /// most `capacity` events.
+    pub fn new(capacity: usize) -> (Self, Receiver<ReportEvent>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let reporter = Self {
+            sender: Mutex::new(sender),
+        };
+        (reporter, receiver)
+    }
+
+    async fn send(&self, event: ReportEvent) -> Result<(), Error> {
+        // lock the sender instead of cloning it, as each clone gets an extra
+        // slot in the channel
+        let mut sender = self.sender.lock().await;
+        // wait for room only, as flushing would wait for the receiver to
+        // consume the event
+        poll_fn(|cx| sender.poll_ready(cx))
+            .await
+            .and_then(|_| sender.start_send(event))
+            .map_err(|e| Error::UnknownError(format!("failed to send report: {}", e)))
+    }
+}
+
+#[async_trait]
+impl AsyncReporter for ChannelReporter {
+    async fn stdout(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.send(ReportEvent::Stdout {
+            context: context.clone(),
+            line: line.into(),
+            timestamp,
+        })
+        .await
+    }
+    async fn stderr(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.send(ReportEvent::Stderr {
+            context: context.clone(),
+            line: line.into(),
+            timestamp,
+        })
+        .await
+    }
+    async fn status(
+        &self,
+        context: &ReportContext,
+        status: &Status,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.send(ReportEvent::Status {
+            context: context.clone(),
+            status: status.clone(),
+            timestamp,
+        })
+        .await
+    }
+    async fn audit(&self, context: &ReportContext, record: &AuditRecord) -> Result<(), Error> {
+        self.send(ReportEvent::Audit {
+            context: context.clone(),
+            record: record.clone(),
+        })
+        .await
     }
-    fn report_stdout(&self, line: &str, timestamp: DateTime<Utc>) -> Result<(), Error>;
-    fn report_stderr(&self, line: &str, timestamp: DateTime<Utc>) -> Result<(), Error>;
 }
 
 pub struct TextReporter;
 
 impl Reporter for TextReporter {
-    fn report_stdout(&self, line: &str, _: DateTime<Utc>) -> Result<(), Error> {
+    fn report_stdout(&self, _: &ReportContext, line: &str, _: DateTime<Utc>) -> Result<(), Error> {
         println!("{}", line);
         Ok(())
     }
-    fn report_stderr(&self, line: &str, _: DateTime<Utc>) -> Result<(), Error> {
+    fn report_stderr(&self, _: &ReportContext, line: &str, _: DateTime<Utc>) -> Result<(), Error> {
         eprintln!("{}", line);
         Ok(())
     }
 }
+
+/// Reporter writing one JSON object per line to stdout for each event, with
+/// the context it happens in.
+pub struct JsonReporter;
+
+/// Event reported by [`JsonReporter`].
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum JsonEvent<'a> {
+    Stdout {
+        timestamp: DateTime<Utc>,
+        #[serde(flatten)]
+        context: &'a ReportContext,
+        line: &'a str,
+    },
+    Stderr {
+        timestamp: DateTime<Utc>,
+        #[serde(flatten)]
+        context: &'a ReportContext,
+        line: &'a str,
+    },
+    Status {
+        timestamp: DateTime<Utc>,
+        #[serde(flatten)]
+        context: &'a ReportContext,
+        #[serde(flatten)]
+        status: &'a Status,
+    },
+    Audit {
+        #[serde(flatten)]
+        context: &'a ReportContext,
+        #[serde(flatten)]
+        record: &'a AuditRecord,
+    },
+}
+
+impl JsonReporter {
+    pub(crate) fn emit(&self, event: &JsonEvent) -> Result<(), Error> {
+        let line = serde_json::to_string(event).map_err(|e| Error::UnknownError(e.to_string()))?;
+        println!("{}", line);
+        Ok(())
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn report_stdout(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.emit(&JsonEvent::Stdout {
+            timestamp,
+            context,
+            line,
+        })
+    }
+    fn report_stderr(
+        &self,
+        context: &ReportContext,
+        line: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.emit(&JsonEvent::Stderr {
+            timestamp,
+            context,
+            line,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        asset::{AssetProgress, AssetState},
+        audit::{AuditEvent, MountAccess},
+    };
+
+    const TIMESTAMP: &str = "2021-10-01T08:00:00Z";
+
+    fn timestamp() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(TIMESTAMP).unwrap().into()
+    }
+
+    fn to_json(event: &JsonEvent) -> Value {
+        serde_json::to_value(event).unwrap()
+    }
+
+    #[test]
+    fn json_status_events() {
+        let context = ReportContext::new("task").stage(0, "build");
+        let progress = |state| {
+            Status::PrepareAssets(Some(AssetProgress {
+                name: "data.zip".into(),
+                state,
+            }))
+        };
+        let cases = vec![
+            (Status::Start, json!({"status": "start"})),
+            (
+                Status::PrepareAssets(None),
+                json!({"status": "prepare_assets", "detail": null}),
+            ),
+            (
+                progress(AssetState::Started),
+                json!({
+                    "status": "prepare_assets",
+                    "detail": {"name": "data.zip", "state": "started"},
+                }),
+            ),
+            (
+                progress(AssetState::Finished(42)),
+                json!({
+                    "status": "prepare_assets",
+                    "detail": {"name": "data.zip", "state": {"finished": 42}},
+                }),
+            ),
+            (
+                progress(AssetState::Failed("timed out".into())),
+                json!({
+                    "status": "prepare_assets",
+                    "detail": {"name": "data.zip", "state": {"failed": "timed out"}},
+                }),
+            ),
+            (
+                Status::BuildStageScript("build".into()),
+                json!({"status": "build_stage_script", "detail": "build"}),
+            ),
+            (
+                Status::RunStage("build".into()),
+                json!({"status": "run_stage", "detail": "build"}),
+            ),
+            (
+                Status::FinishStage("build".into()),
+                json!({"status": "finish_stage", "detail": "build"}),
+            ),
+            (Status::Success, json!({"status": "success"})),
+            (
+                Status::Error("failed".into()),
+                json!({"status": "error", "detail": "failed"}),
+            ),
+        ];
+        for (status, expected) in cases {
+            let mut expected = expected;
+            let fields = expected.as_object_mut().unwrap();
+            fields.insert("type".into(), json!("status"));
+            fields.insert("timestamp".into(), json!(TIMESTAMP));
+            fields.insert("task_id".into(), json!("task"));
+            fields.insert("stage".into(), json!("build"));
+            fields.insert("stage_index".into(), json!(0));
+            fields.insert("attempt".into(), json!(1));
+            let event = JsonEvent::Status {
+                timestamp: timestamp(),
+                context: &context,
+                status: &status,
+            };
+            assert_eq!(to_json(&event), expected, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn json_output_events() {
+        let context = ReportContext::new("task");
+        let event = JsonEvent::Stderr {
+            timestamp: timestamp(),
+            context: &context,
+            line: "oops",
+        };
+        assert_eq!(
+            to_json(&event),
+            json!({
+                "type": "stderr",
+                "timestamp": TIMESTAMP,
+                "task_id": "task",
+                "attempt": 1,
+                "line": "oops",
+            })
+        );
+    }
+
+    #[test]
+    fn json_audit_event() {
+        let context = ReportContext::new("task");
+        let record = AuditRecord {
+            timestamp: timestamp(),
+            event: AuditEvent::Mount {
+                source: "./data".into(),
+                resolved: Some("/home/user/data".into()),
+                target: "/data".into(),
+                access: MountAccess::ReadOnly,
+            },
+        };
+        let event = JsonEvent::Audit {
+            context: &context,
+            record: &record,
+        };
+        assert_eq!(
+            to_json(&event),
+            json!({
+                "type": "audit",
+                "timestamp": TIMESTAMP,
+                "task_id": "task",
+                "attempt": 1,
+                "kind": "mount",
+                "source": "./data",
+                "resolved": "/home/user/data",
+                "target": "/data",
+                "access": "read_only",
+            })
+        );
+    }
+
+    fn line(event: Option<ReportEvent>) -> String {
+        match event {
+            Some(ReportEvent::Stdout { line, .. }) => line,
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_waits_when_full() {
+        let context = ReportContext::new("task");
+        let (reporter, mut receiver) = ChannelReporter::new(1);
+        // the sender has a slot of its own besides the buffer
+        for l in ["1", "2"] {
+            reporter.stdout(&context, l, Utc::now()).await.unwrap();
+        }
+        let blocked = tokio::time::timeout(
+            Duration::from_millis(50),
+            reporter.stdout(&context, "dropped", Utc::now()),
+        )
+        .await;
+        assert!(blocked.is_err());
+
+        let (sent, received) =
+            tokio::join!(reporter.stdout(&context, "3", Utc::now()), receiver.next());
+        sent.unwrap();
+        assert_eq!(line(received), "1");
+        assert_eq!(line(receiver.next().await), "2");
+        assert_eq!(line(receiver.next().await), "3");
+    }
+
+    #[tokio::test]
+    async fn channel_fails_without_receiver() {
+        let context = ReportContext::new("task");
+        let (reporter, receiver) = ChannelReporter::new(1);
+        drop(receiver);
+        let result = reporter.status(&context, &Status::Start, Utc::now()).await;
+        assert!(matches!(result, Err(Error::UnknownError(_))));
+    }
+
+    /// Sync reporter collecting what is reported.
+    #[derive(Default)]
+    struct Collector(std::sync::Mutex<Vec<String>>);
+
+    impl Reporter for Collector {
+        fn report_stdout(
+            &self,
+            _: &ReportContext,
+            line: &str,
+            _: DateTime<Utc>,
+        ) -> Result<(), Error> {
+            self.0.lock().unwrap().push(format!("stdout: {}", line));
+            Ok(())
+        }
+        fn report_stderr(
+            &self,
+            _: &ReportContext,
+            line: &str,
+            _: DateTime<Utc>,
+        ) -> Result<(), Error> {
+            self.0.lock().unwrap().push(format!("stderr: {}", line));
+            Ok(())
+        }
+    }
+
+    impl RunnerReporter for Collector {
+        fn report_status(
+            &self,
+            _: &ReportContext,
+            status: &Status,
+            _: DateTime<Utc>,
+        ) -> Result<(), Error> {
+            self.0.lock().unwrap().push(format!("status: {:?}", status));
+            Ok(())
+        }
+        fn report_audit(&self, _: &ReportContext, record: &AuditRecord) -> Result<(), Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("audit: {:?}", record.event));
+            Ok(())
+        }
+    }
+
+    async fn report_all(reporter: &impl AsyncReporter) -> Result<(), Error> {
+        let context = ReportContext::new("task");
+        reporter.stdout(&context, "out", Utc::now()).await?;
+        reporter.stderr(&context, "err", Utc::now()).await?;
+        reporter
+            .status(&context, &Status::Success, Utc::now())
+            .await?;
+        let record = AuditRecord::new(AuditEvent::Env {
+            name: "HOME".into(),
+            granted: true,
+        });
+        reporter.audit(&context, &record).await
+    }
+
+    #[tokio::test]
+    async fn adapt_sync_reporter() {
+        let reporter = Collector::default();
+        report_all(&reporter).await.unwrap();
+        assert_eq!(
+            *reporter.0.lock().unwrap(),
+            vec![
+                "stdout: out",
+                "stderr: err",
+                "status: Success",
+                "audit: Env { name: \"HOME\", granted: true }",
+            ]
+        );
+    }
+}
@@ -3,21 +3,28 @@
 use std::collections::HashMap;
 
 use chrono::{DateTime, Utc};
+use serde::Serialize;
 
 use crate::{
-    asset::AssetManager,
-    permission::Permissions,
-    reporter::{Reporter, TextReporter},
+    asset::{AssetLimits, AssetManager, AssetSpec},
+    audit::AuditRecord,
+    cache::AssetCacheConfig,
+    permission::{PermissionRequirements, Permissions},
+    reporter::{AsyncReporter, JsonEvent, JsonReporter, ReportContext, Reporter, TextReporter},
     sandbox::{RunOptions, Sandbox},
+    secret::{Secret, SecretSpec, Secrets},
     Error,
 };
 
+pub use crate::asset::{AssetProgress, AssetState};
 pub use crate::sandbox::RunOptions as StageSpec;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", content = "detail", rename_all = "snake_case")]
 pub enum Status {
     Start,
-    PrepareAssets,
+    /// Preparing assets, with progress of each asset after started.
+    PrepareAssets(Option<AssetProgress>),
     BuildStageScript(String),
     RunStage(String),
     FinishStage(String),
@@ -29,88 +36,264 @@ pub enum Status {
 /// and report the process.
 ///
 /// You should always initiate a new runner for each task.
-pub struct Runner<'sandbox, TReporter: RunnerReporter> {
+pub struct Runner<'sandbox, TReporter: AsyncReporter> {
     sandbox: Sandbox<'sandbox>,
     status: Status,
     assets: AssetManager,
     permisssions: Permissions,
     reporter: TReporter,
+    audit: Vec<AuditRecord>,
+    provided_secrets: HashMap<String, Secret>,
+    secrets: Secrets,
+    context: ReportContext,
+    next_stage: usize,
 }
 
 impl Runner<'_, TextReporter> {
     pub fn new(
         docker: &bollard::Docker,
         permissions: Option<Permissions>,
-    ) -> Result<Runner<TextReporter>, Error> {
+    ) -> Result<Runner<'_, TextReporter>, Error> {
+        Runner::with_reporter(docker, permissions, TextReporter {})
+    }
+}
+
+impl<T: AsyncReporter> Runner<'_, T> {
+    /// Create a runner reporting to the given reporter.
+    pub fn with_reporter(
+        docker: &bollard::Docker,
+        permissions: Option<Permissions>,
+        reporter: T,
+    ) -> Result<Runner<'_, T>, Error> {
         Ok(Runner {
             sandbox: Sandbox::new(docker),
             assets: AssetManager::new()?,
-            reporter: TextReporter {},
-            permisssions: match permissions {
-                Some(p) => p,
-                _ => Permissions::default(),
-            },
+            reporter,
+            permisssions: permissions.unwrap_or_default(),
             status: Status::Start,
+            audit: vec![],
+            provided_secrets: HashMap::new(),
+            secrets: Secrets::default(),
+            context: ReportContext::new(generate_task_id()),
+            next_stage: 0,
         })
     }
 }
 
-impl<T: RunnerReporter> Runner<'_, T> {
-    fn set_status(&mut self, status: Status) -> Result<(), HandledError> {
+impl<T: AsyncReporter> Runner<'_, T> {
+    /// Set ID of the task reported in every event, generated by default.
+    pub fn set_task_id(&mut self, id: impl Into<String>) {
+        self.context.task_id = id.into();
+    }
+    /// Set which attempt of running the task this is, starting from 1.
+    pub fn set_attempt(&mut self, attempt: u32) {
+        self.context.attempt = attempt;
+    }
+    /// Set where and how downloaded assets are cached between runs.
+    pub fn set_asset_cache(&mut self, config: AssetCacheConfig) {
+        self.assets.set_cache_config(config);
+    }
+    /// Provide secret values that tasks refer to with `{ provided: <key> }`.
+    pub fn set_secrets(&mut self, secrets: HashMap<String, Secret>) {
+        self.provided_secrets = secrets;
+    }
+    /// Set parallelism, size and time limits on fetching assets.
+    pub fn set_asset_limits(&mut self, limits: AssetLimits) {
+        self.assets.set_limits(limits);
+    }
+    async fn set_status(&mut self, status: Status) -> Result<(), HandledError> {
+        self.flush_audit().await?;
         log::info!("changing status: {:?} -> {:?}", self.status, status);
         self.status = status;
         // do not report error again when reporting has failed
-        self.reporter.emit_status(&self.status).ignore()?;
+        self.reporter
+            .status(&self.context, &self.status, Utc::now())
+            .await
+            .ignore()?;
+        Ok(())
+    }
+    pub async fn check_permissions(
+        &mut self,
+        requirements: &PermissionRequirements,
+    ) -> Result<(), HandledError> {
+        log::info!("checking required permissions: {:?}", requirements);
+        self.permisssions
+            .check_requirements(requirements)
+            .handle(self)
+            .await?;
+        Ok(())
+    }
+    pub async fn prepare_secrets(
+        &mut self,
+        secrets: HashMap<String, SecretSpec>,
+    ) -> Result<(), HandledError> {
+        log::info!("resolving secrets: {:?}", secrets.keys());
+        self.secrets = Secrets::resolve(secrets, &self.provided_secrets, &mut self.permisssions)
+            .handle(self)
+            .await?;
         Ok(())
     }
     pub async fn prepare_assets(
         &mut self,
-        assets: HashMap<String, String>,
+        assets: HashMap<String, AssetSpec>,
     ) -> Result<(), HandledError> {
-        self.set_status(Status::PrepareAssets)?;
-        self.assets.prepare(assets).await.handle(self)?;
+        self.set_status(Status::PrepareAssets(None)).await?;
+        let reporter = &self.reporter;
+        let context = &self.context;
+        self.assets
+            .prepare(assets, &mut self.permisssions, |p| {
+                report_progress(reporter, context, p)
+            })
+            .await
+            .handle(self)
+            .await?;
         Ok(())
     }
-    pub async fn run_stage(&mut self, name: &str, stage: StageSpec) -> Result<(), HandledError> {
+    pub async fn run_stage(
+        &mut self,
+        name: &str,
+        mut stage: StageSpec,
+    ) -> Result<(), HandledError> {
         log::info!("running stage: {}", name);
+        self.context = self.context.stage(self.next_stage, name);
+        self.next_stage += 1;
+
+        // prepare stage assets separately, so that other stages cannot see them
+        let stage_assets = if stage.assets.is_empty() {
+            None
+        } else {
+            log::info!("prepare assets for `{}`", name);
+            self.set_status(Status::PrepareAssets(None)).await?;
+            let assets = self.assets.fork().handle(self).await?;
+            let reporter = &self.reporter;
+            let context = &self.context;
+            assets
+                .prepare(
+                    std::mem::take(&mut stage.assets),
+                    &mut self.permisssions,
+                    |p| report_progress(reporter, context, p),
+                )
+                .await
+                .handle(self)
+                .await?;
+            Some(assets)
+        };
 
         log::info!("build stage script for `{}`", name);
-        self.set_status(Status::BuildStageScript(name.into()))?;
+        self.set_status(Status::BuildStageScript(name.into()))
+            .await?;
+        self.permisssions
+            .use_image(&stage.image)
+            .handle(self)
+            .await?;
+        self.flush_audit().await?;
         let image = self
             .sandbox
-            .build(&stage.image, &stage.extend)
+            .build(&stage.image, &stage.extend, &mut self.permisssions)
             .await
-            .handle(self)?;
+            .handle(self)
+            .await?;
+        self.flush_audit().await?;
 
         log::info!("run stage `{}` with image: {}", name, image);
-        self.set_status(Status::RunStage(name.into()))?;
+        self.set_status(Status::RunStage(name.into())).await?;
 
         self.sandbox
             .run(
                 RunOptions { image, ..stage },
-                &self.assets,
-                &self.permisssions,
+                stage_assets.as_ref().unwrap_or(&self.assets),
+                &mut self.permisssions,
+                &self.secrets,
                 &self.reporter,
+                &self.context,
             )
             .await
-            .handle(self)?;
+            .handle(self)
+            .await?;
+        self.flush_audit().await?;
+
+        self.set_status(Status::FinishStage(name.into())).await?;
+        self.context.stage = None;
+        self.context.stage_index = None;
+        Ok(())
+    }
 
+    /// Get audit records of the task so far, which are kept even if the task
+    /// has failed.
+    pub fn audit(&self) -> &[AuditRecord] {
+        &self.audit
+    }
+
+    /// Get result of the task so far.
+    pub fn result(&self) -> RunResult {
+        RunResult {
+            task_id: self.context.task_id.clone(),
+            audit: self.audit.clone(),
+        }
+    }
+
+    /// Report success if no error has occurred. This should always be awaited
+    /// after running all stages, as dropping the runner does not report.
+    pub async fn finish(&mut self) -> Result<(), HandledError> {
+        if matches!(self.status, Status::Error(_) | Status::Success) {
+            return Ok(());
+        }
+        // indicates that all stages finished successfully
+        self.set_status(Status::Success).await
+    }
+
+    async fn flush_audit(&mut self) -> Result<(), HandledError> {
+        for record in self.permisssions.take_audit() {
+            // do not report error again when reporting has failed
+            self.reporter.audit(&self.context, &record).await.ignore()?;
+            self.audit.push(record);
+        }
         Ok(())
     }
 }
 
-impl<T: RunnerReporter> Drop for Runner<'_, T> {
+/// Generate a task ID unique on this host.
+fn generate_task_id() -> String {
+    format!("{}-{}", std::process::id(), Utc::now().timestamp_nanos())
+}
+
+/// Report progress of a single asset, which does not change runner status.
+async fn report_progress(
+    reporter: &impl AsyncReporter,
+    context: &ReportContext,
+    progress: AssetProgress,
+) {
+    let status = Status::PrepareAssets(Some(progress));
+    if let Err(e) = reporter.status(context, &status, Utc::now()).await {
+        log::warn!("failed to report asset progress: {:?}", e);
+    }
+}
+
+/// Result of a task run.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RunResult {
+    pub task_id: String,
+    /// Permission decisions made during the run.
+    pub audit: Vec<AuditRecord>,
+}
+
+impl<T: AsyncReporter> Drop for Runner<'_, T> {
     fn drop(&mut self) {
-        if matches!(self.status, Status::Error(_)) {
-            // runner is already dead, and the error has been reported
+        if matches!(self.status, Status::Error(_) | Status::Success) {
+            // runner is already dead and the error has been reported, or
+            // success has been reported by `finish`
             return;
         }
         if std::thread::panicking() {
             // do not report when panicking
             return;
         }
-        // indicates that all stages finished successfully
-        self.set_status(Status::Success).unwrap();
+        // reporting is async and might need the runtime this is dropped in,
+        // so it cannot be done here
+        log::warn!(
+            "runner of task {} dropped without awaiting `finish`, final status is not reported",
+            self.context.task_id
+        );
     }
 }
 
@@ -119,7 +302,7 @@ impl<T: RunnerReporter> Drop for Runner<'_, T> {
 pub struct HandledError(pub Error);
 
 trait ErrorHandler<T> {
-    fn handle(self, runner: &mut Runner<impl RunnerReporter>) -> Result<T, HandledError>;
+    async fn handle(self, runner: &mut Runner<'_, impl AsyncReporter>) -> Result<T, HandledError>;
     fn ignore(self) -> Result<T, HandledError>;
 }
 
@@ -127,10 +310,11 @@ impl<T, E> ErrorHandler<T> for Result<T, E>
 where
     E: Into<Error> + std::fmt::Debug,
 {
-    fn handle(self, r: &mut Runner<impl RunnerReporter>) -> Result<T, HandledError> {
+    async fn handle(self, r: &mut Runner<'_, impl AsyncReporter>) -> Result<T, HandledError> {
         match self {
             Err(e) => {
-                r.set_status(Status::Error(format!("{:?}", e)))?;
+                let message = r.secrets.redact(&format!("{:?}", e));
+                r.set_status(Status::Error(message)).await?;
                 Err(HandledError(e.into()))
             }
             Ok(r) => Ok(r),
@@ -147,19 +331,54 @@ impl From<HandledError> for Error {
     }
 }
 
-/// Report status
+/// Report status. See [`AsyncReporter`] for reporters that need to wait.
 pub trait RunnerReporter: Reporter {
-    fn emit_status(&self, status: &Status) -> Result<(), Error> {
-        self.report_status(status, Utc::now())
+    fn emit_status(&self, context: &ReportContext, status: &Status) -> Result<(), Error> {
+        self.report_status(context, status, Utc::now())
+    }
+    fn report_status(
+        &self,
+        context: &ReportContext,
+        status: &Status,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error>;
+    fn report_audit(&self, _context: &ReportContext, _record: &AuditRecord) -> Result<(), Error> {
+        Ok(())
     }
-    fn report_status(&self, status: &Status, timestamp: DateTime<Utc>) -> Result<(), Error>;
 }
 
 impl RunnerReporter for TextReporter {
-    fn report_status(&self, status: &Status, _: DateTime<Utc>) -> Result<(), Error> {
+    fn report_status(
+        &self,
+        _: &ReportContext,
+        status: &Status,
+        _: DateTime<Utc>,
+    ) -> Result<(), Error> {
         if let Status::Error(e) = status {
             log::warn!("error: {:?}", e);
         }
         Ok(())
     }
+    fn report_audit(&self, _: &ReportContext, record: &AuditRecord) -> Result<(), Error> {
+        log::info!("audit: {:?}", record.event);
+        Ok(())
+    }
+}
+
+impl RunnerReporter for JsonReporter {
+    fn report_status(
+        &self,
+        context: &ReportContext,
+        status: &Status,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        self.emit(&JsonEvent::Status {
+            timestamp,
+            context,
+            status,
+        })
+    }
+    fn report_audit(&self, context: &ReportContext, record: &AuditRecord) -> Result<(), Error> {
+        self.emit(&JsonEvent::Audit { context, record })
+    }
 }
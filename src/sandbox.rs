@@ -5,13 +5,24 @@ use std::path::Path;
 use std::str::from_utf8;
 
 use bollard::container::{Config, LogOutput, LogsOptions};
+use bollard::exec::{CreateExecOptions, StartExecResults};
 use bollard::image::BuildImageOptions;
-use bollard::models::HostConfig;
+use bollard::models::{HostConfig, MountTmpfsOptions, MountTypeEnum};
 use bollard::Docker;
 use futures::future::join;
 use futures::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::io::AsyncWriteExt;
 
-use crate::{permission::Permissions, AssetManager, Error, Reporter};
+use crate::{
+    asset::AssetSpec,
+    audit::{NetworkAccess, NetworkPhase},
+    egress::EgressProxy,
+    permission::{NetDescriptor, Permissions},
+    reporter::{split_timestamp, AsyncReporter, ReportContext},
+    secret::{Secrets, SECRETS_DIR},
+    AssetManager, ByteSize, Error,
+};
 
 /// Represents a sandboxed environment for task building and running.
 pub struct Sandbox<'docker> {
@@ -20,12 +31,49 @@ pub struct Sandbox<'docker> {
 
 impl Sandbox<'_> {
     /// Create a new sandbox environment.
-    pub fn new(docker: &Docker) -> Sandbox {
+    pub fn new(docker: &Docker) -> Sandbox<'_> {
         Sandbox { docker }
     }
 
     /// Build docker image and return image ID.
-    pub async fn build(&self, image: &str, extend: &[String]) -> Result<String, Error> {
+    pub async fn build(
+        &self,
+        image: &str,
+        extend: &[String],
+        permissions: &mut Permissions,
+    ) -> Result<String, Error> {
+        let mut options = BuildImageOptions::<String>::default();
+
+        let proxy = match permissions.network(NetworkPhase::Build) {
+            NetworkAccess::Enabled => None,
+            NetworkAccess::Disabled => {
+                log::info!("network disabled for build");
+                options.networkmode = "none".into();
+                None
+            }
+            NetworkAccess::Filtered(hosts) => {
+                log::info!("network restricted to {:?} for build", hosts);
+                Some(EgressProxy::start(self.docker, &net_descriptors(hosts)).await?)
+            }
+        };
+        if let Some(proxy) = &proxy {
+            options.networkmode = proxy.network().into();
+            options.buildargs.extend(proxy.envs());
+        }
+
+        let result = self.build_image(image, extend, options).await;
+        if let Some(proxy) = proxy {
+            proxy.stop().await?;
+        }
+        result
+    }
+
+    async fn build_image(
+        &self,
+        image: &str,
+        extend: &[String],
+        options: BuildImageOptions<String>,
+    ) -> Result<String, Error> {
         let dir = tempfile::tempdir()?;
         let dir_path = dir.path().to_str().expect("tempdir should always be valid");
 
@@ -39,7 +87,6 @@ impl Sandbox<'_> {
             }
         }
 
-        let options = BuildImageOptions::<String>::default();
         let mut bytes = vec![];
         tarball::dir(&mut bytes, dir_path)?;
         let mut stream = self
@@ -60,7 +107,6 @@ impl Sandbox<'_> {
                         if let Some(id) = aux.id {
                             // extract image sha256 and return
                             // id is given in the form of "sha256:<id>" (with quotes)
-                            let id = id;
                             let id = id
                                 .trim_matches('"')
                                 .split(':')
@@ -85,13 +131,16 @@ impl Sandbox<'_> {
         Err(Error::UnknownError("image not successfully built".into()))
     }
 
-    /// Run scripts with envs.
+    /// Run scripts with envs and secrets. Secrets are masked in every line
+    /// reported.
     pub async fn run(
         &self,
         options: RunOptions,
         asset: &AssetManager,
-        permissions: &Permissions,
-        reporter: &impl Reporter,
+        permissions: &mut Permissions,
+        secrets: &Secrets,
+        reporter: &impl AsyncReporter,
+        context: &ReportContext,
     ) -> Result<(), Error> {
         log::info!(
             "create container using {} with envs {:?}",
@@ -103,7 +152,7 @@ impl Sandbox<'_> {
         let file_path = asset_path.join(".run.sh");
         log::debug!("writing stage script at: {:?}", file_path);
         let mut file = File::create(file_path)?;
-        for line in options.script.iter() {
+        for line in secrets.prelude().iter().chain(options.script.iter()) {
             writeln!(file, "{}", line)?;
         }
         file.flush()?;
@@ -115,58 +164,106 @@ impl Sandbox<'_> {
                 .to_str()
                 .expect("path should always be valid utf-8 string")
         ));
-        for (k, v) in options.mounts.iter() {
-            let path = Path::new(v).canonicalize()?;
-            binds.push(format!(
-                "{}:{}{}",
-                path.to_str()
-                    .expect("path should always be valid utf-8 string"),
-                k,
-                if permissions.write.check(&path).is_ok() {
-                    ""
-                } else if let Err(e) = permissions.read.check(&path) {
-                    return Err(e);
-                } else {
-                    ":ro"
+        let mut mounts = vec![];
+        for mount in options.mounts.iter() {
+            let read_only = mount.mode.map(|mode| mode == MountMode::Ro);
+            match mount.kind {
+                MountType::Bind => {
+                    let source = mount.source()?;
+                    let (path, read_only) =
+                        permissions.mount(Path::new(source), &mount.target, read_only)?;
+                    binds.push(format!(
+                        "{}:{}{}",
+                        path.to_str()
+                            .expect("path should always be valid utf-8 string"),
+                        mount.target,
+                        if read_only { ":ro" } else { "" }
+                    ));
+                }
+                MountType::Volume => {
+                    let source = mount.source()?;
+                    let read_only = permissions.mount_volume(source, &mount.target, read_only)?;
+                    log::info!("mount volume {} to {}", source, mount.target);
+                    mounts.push(bollard::models::Mount {
+                        target: Some(mount.target.clone()),
+                        source: Some(source.into()),
+                        typ: Some(MountTypeEnum::VOLUME),
+                        read_only: Some(read_only),
+                        ..Default::default()
+                    });
                 }
-            ));
+                MountType::Tmpfs => {
+                    mounts.push(bollard::models::Mount {
+                        target: Some(mount.target.clone()),
+                        typ: Some(MountTypeEnum::TMPFS),
+                        read_only,
+                        tmpfs_options: Some(MountTmpfsOptions {
+                            size_bytes: mount.size.map(|size| size.0 as i64),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    });
+                }
+            }
         }
 
-        let config = Config {
-            image: Some(options.image),
-            tty: Some(true),
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            env: Some(
-                options
-                    .envs
-                    .iter()
-                    .map(|(k, v)| format!("{}={}", k, v))
-                    .collect::<Vec<String>>(),
-            ),
-            network_disabled: if permissions.net.check().is_ok() {
-                None
-            } else {
-                Some(true)
-            },
-            stop_timeout: Some(3 * 60),
-            working_dir: Some(options.workdir),
-            cmd: Some(
-                vec!["sh", "-e", "/assets/.run.sh"]
-                    .into_iter()
-                    .map(|s| s.to_string())
-                    .collect(),
-            ),
-            host_config: Some(HostConfig {
-                nano_cpus: Some(1_000_000_000),
-                memory: Some(1 << 30),
-                binds: Some(binds),
-                auto_remove: Some(true),
-                ..Default::default()
-            }),
+        let mut envs = vec![];
+        for (k, v) in options.envs.iter() {
+            envs.push(format!("{}={}", k, v.resolve(permissions)?));
+        }
+
+        // build the whole host config before starting the proxy, so that
+        // nothing fails without stopping it
+        let mut host_config = HostConfig {
+            binds: Some(binds),
+            mounts: Some(mounts),
+            auto_remove: Some(true),
             ..Default::default()
         };
+        permissions.resources.apply(&mut host_config);
+        permissions.security.apply(&mut host_config)?;
+
+        let network = permissions.network(NetworkPhase::Run);
+        let proxy = match &network {
+            NetworkAccess::Enabled => None,
+            NetworkAccess::Disabled => {
+                log::info!("network disabled");
+                None
+            }
+            NetworkAccess::Filtered(hosts) => {
+                log::info!("network restricted to: {:?}", hosts);
+                Some(EgressProxy::start(self.docker, &net_descriptors(hosts.clone())).await?)
+            }
+        };
+        if let Some(proxy) = &proxy {
+            envs.extend(proxy.envs().iter().map(|(k, v)| format!("{}={}", k, v)));
+        }
+        host_config.network_mode = proxy.as_ref().map(|p| p.network().to_string());
 
+        let config = container_config(
+            options.image,
+            options.workdir,
+            envs,
+            secrets,
+            permissions.security.user.clone(),
+            network == NetworkAccess::Disabled,
+            host_config,
+        );
+
+        let result = self.run_container(config, secrets, reporter, context).await;
+        if let Some(proxy) = proxy {
+            proxy.stop().await?;
+        }
+        result
+    }
+
+    async fn run_container(
+        &self,
+        config: Config<String>,
+        secrets: &Secrets,
+        reporter: &impl AsyncReporter,
+        context: &ReportContext,
+    ) -> Result<(), Error> {
         let container = self
             .docker
             .create_container::<String, String>(None, config)
@@ -180,34 +277,93 @@ impl Sandbox<'_> {
 
         log::info!("container started");
 
+        if let Err(e) = self.write_secrets(&container.id, secrets).await {
+            // the script would wait for secrets forever
+            let _ = self
+                .docker
+                .kill_container::<String>(&container.id, None)
+                .await;
+            return Err(e);
+        }
+
         log::debug!("processing logs and wait for container to finish");
 
-        let log_op = self.process_logs(&container.id, reporter);
+        let log_op = self.process_logs(&container.id, secrets, reporter, context);
         let mut stream = self.docker.wait_container::<String>(&container.id, None);
         let wait_op = stream.next();
         let (log, exit) = join(log_op, wait_op).await;
 
-        let _ = log?;
+        log?;
         let e =
             exit.ok_or_else(|| Error::UnknownError("failed to fetch wait response".into()))??;
 
         log::info!("container exited with code {}", e.status_code);
         if e.status_code > 0 {
             // report exit code if failed
-            reporter.report_stderr(
-                &format!("[program exited with code {}]", e.status_code),
-                chrono::Utc::now(),
-            )?;
+            reporter
+                .stderr(
+                    context,
+                    &format!("[program exited with code {}]", e.status_code),
+                    chrono::Utc::now(),
+                )
+                .await?;
             return Err(Error::ErrorCode(e.status_code as u64));
         }
 
         Ok(())
     }
 
+    /// Write file secrets into the started container through stdin of `sh`,
+    /// so that their values are not kept by docker.
+    async fn write_secrets(&self, container_id: &str, secrets: &Secrets) -> Result<(), Error> {
+        for (path, content) in secrets.files() {
+            log::debug!("writing secret file: {}", path);
+            let exec = self
+                .docker
+                .create_exec(
+                    container_id,
+                    CreateExecOptions {
+                        attach_stdin: Some(true),
+                        attach_stdout: Some(true),
+                        attach_stderr: Some(true),
+                        cmd: Some(vec!["sh", "-c", "umask 077; cat > \"$0\"", &path]),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            let mut errors = String::new();
+            if let StartExecResults::Attached {
+                mut output,
+                mut input,
+            } = self.docker.start_exec(&exec.id, None).await?
+            {
+                input.write_all(content.as_bytes()).await?;
+                // close stdin so that cat finishes
+                input.shutdown().await?;
+                while let Some(chunk) = output.next().await {
+                    errors.push_str(&chunk?.to_string());
+                }
+            }
+            let inspect = self.docker.inspect_exec(&exec.id).await?;
+            if inspect.exit_code != Some(0) {
+                return Err(Error::UnknownError(format!(
+                    "failed to write {}: {}",
+                    path,
+                    errors.trim()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Report container output line by line, with secrets masked before
+    /// the lines are logged or reported.
     async fn process_logs(
         &self,
         container_id: &str,
-        reporter: &impl Reporter,
+        secrets: &Secrets,
+        reporter: &impl AsyncReporter,
+        context: &ReportContext,
     ) -> Result<(), Error> {
         let mut stream = self.docker.logs::<String>(
             container_id,
@@ -219,6 +375,10 @@ impl Sandbox<'_> {
             }),
         );
 
+        // tty output comes in arbitrary chunks, collect whole lines so that
+        // secrets are redacted even if printed across chunks
+        let mut console = LineBuffer::default();
+
         // TODO: get limit from configuration
         let mut limit = 500;
         while let Some(exec_result) = stream.next().await {
@@ -229,27 +389,120 @@ impl Sandbox<'_> {
             let chunk = exec_result?;
             match chunk {
                 LogOutput::StdOut { message: bytes } => {
-                    let line = from_utf8(&bytes)?;
+                    let line = secrets.redact(from_utf8(&bytes)?);
                     log::debug!("stdout | {}", line.trim_end());
-                    reporter.emit_stdout(line)?;
+                    let (timestamp, line) = split_timestamp(&line);
+                    reporter.stdout(context, line, timestamp).await?;
                 }
                 LogOutput::StdErr { message: bytes } => {
-                    let line = from_utf8(&bytes)?;
+                    let line = secrets.redact(from_utf8(&bytes)?);
                     log::debug!("stderr | {}", line.trim_end());
-                    reporter.emit_stderr(line)?;
+                    let (timestamp, line) = split_timestamp(&line);
+                    reporter.stderr(context, line, timestamp).await?;
                 }
                 LogOutput::Console { message: bytes } => {
-                    let line = from_utf8(&bytes)?;
-                    log::debug!("console | {}", line.trim_end());
-                    reporter.emit_console(line)?;
+                    for line in console.push(&bytes) {
+                        let line = secrets.redact(from_utf8(&line)?);
+                        log::debug!("console | {}", line);
+                        reporter.stdout(context, &line, chrono::Utc::now()).await?;
+                    }
                 }
                 _ => unreachable!(),
             };
         }
+        if let Some(line) = console.finish() {
+            let line = secrets.redact(from_utf8(&line)?);
+            log::debug!("console | {}", line);
+            reporter.stdout(context, &line, chrono::Utc::now()).await?;
+        }
         Ok(())
     }
 }
 
+/// Longest line kept in [`LineBuffer`] before it is passed on anyway.
+const MAX_LINE_LENGTH: usize = 64 << 10;
+
+/// Buffer splitting chunks of output into lines.
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Add a chunk, returning all lines completed by it without line endings.
+    fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(chunk);
+        let mut lines = vec![];
+        while let Some(i) = self.pending.iter().position(|&b| b == b'\n') {
+            let mut line = self.pending.drain(..=i).collect::<Vec<_>>();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            lines.push(line);
+        }
+        if self.pending.len() > MAX_LINE_LENGTH {
+            // do not hold output forever, e.g. progress bars without newlines
+            let end = match from_utf8(&self.pending) {
+                // keep an incomplete character for the next chunk
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                _ => self.pending.len(),
+            };
+            lines.push(self.pending.drain(..end).collect());
+        }
+        lines
+    }
+
+    /// Get the last line not ended with a newline.
+    fn finish(self) -> Option<Vec<u8>> {
+        Some(self.pending).filter(|line| !line.is_empty())
+    }
+}
+
+/// Config of the container running the stage script. File secrets are left
+/// out, as docker keeps the config on disk and shows it in `docker inspect`,
+/// and only get a tmpfs to be written into after the container starts.
+fn container_config(
+    image: String,
+    workdir: String,
+    mut envs: Vec<String>,
+    secrets: &Secrets,
+    user: Option<String>,
+    network_disabled: bool,
+    mut host_config: HostConfig,
+) -> Config<String> {
+    envs.extend(secrets.envs());
+    if secrets.has_files() {
+        host_config.tmpfs.get_or_insert_with(HashMap::new).insert(
+            SECRETS_DIR.into(),
+            "rw,nosuid,nodev,noexec,size=1m,mode=1777".into(),
+        );
+    }
+    Config {
+        image: Some(image),
+        user,
+        tty: Some(true),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        env: Some(envs),
+        network_disabled: if network_disabled { Some(true) } else { None },
+        stop_timeout: Some(3 * 60),
+        working_dir: Some(workdir),
+        cmd: Some(
+            vec!["sh", "-e", "/assets/.run.sh"]
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect(),
+        ),
+        host_config: Some(host_config),
+        ..Default::default()
+    }
+}
+
+fn net_descriptors(hosts: Vec<String>) -> Vec<NetDescriptor> {
+    hosts.into_iter().map(NetDescriptor).collect()
+}
+
 /// Defines a stage to be run by runner.
 #[derive(Debug)]
 pub struct RunOptions {
@@ -257,8 +510,108 @@ pub struct RunOptions {
     pub(crate) extend: Vec<String>,
     pub(crate) workdir: String,
     pub(crate) script: Vec<String>,
-    pub(crate) envs: HashMap<String, String>,
-    pub(crate) mounts: HashMap<String, String>,
+    pub(crate) envs: HashMap<String, EnvValue>,
+    pub(crate) mounts: Vec<Mount>,
+    /// Assets only for this stage, in addition to the prepared ones.
+    pub(crate) assets: HashMap<String, AssetSpec>,
+}
+
+/// Something mounted into the container.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    /// Host path for bind mounts, or volume name for volumes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Path inside the container.
+    pub target: String,
+    /// Access mode, inferred from permissions if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<MountMode>,
+    #[serde(default, rename = "type")]
+    pub kind: MountType,
+    /// Size limit of tmpfs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<ByteSize>,
+}
+
+impl Mount {
+    /// Bind mount from host path to target.
+    pub fn bind(source: &str, target: &str) -> Self {
+        Self {
+            source: Some(source.into()),
+            target: target.into(),
+            mode: None,
+            kind: MountType::Bind,
+            size: None,
+        }
+    }
+
+    fn source(&self) -> Result<&str, Error> {
+        self.source
+            .as_deref()
+            .ok_or_else(|| Error::SpecError(format!("mount source required for {}", self.target)))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountMode {
+    Ro,
+    Rw,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountType {
+    #[default]
+    Bind,
+    Volume,
+    Tmpfs,
+}
+
+/// Value of an environment variable set for a stage.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum EnvValue {
+    /// Literal value. Numbers and booleans are converted to strings.
+    Value(String),
+    /// Value of an environment variable on the host, e.g. `{ host: CI_TOKEN }`.
+    Host { host: String },
+}
+
+impl<'de> Deserialize<'de> for EnvValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bool(bool),
+            Number(serde_json::Number),
+            Text(String),
+            Host { host: String },
+        }
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Bool(b) => EnvValue::Value(b.to_string()),
+            Raw::Number(n) => EnvValue::Value(n.to_string()),
+            Raw::Text(s) => EnvValue::Value(s),
+            Raw::Host { host } => EnvValue::Host { host },
+        })
+    }
+}
+
+impl EnvValue {
+    /// Get the actual value, checking env permission for host variables.
+    pub fn resolve(&self, permissions: &mut Permissions) -> Result<String, Error> {
+        match self {
+            EnvValue::Value(v) => Ok(v.clone()),
+            EnvValue::Host { host } => {
+                permissions.forward_env(host)?;
+                std::env::var(host).map_err(|_| {
+                    Error::SpecError(format!("environment variable {} not set on host", host))
+                })
+            }
+        }
+    }
 }
 
 mod tarball {
@@ -329,3 +682,96 @@ mod tarball {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_env_values() {
+        let envs: HashMap<String, EnvValue> = serde_yaml::from_str(
+            "
+NAME: srun
+PORT: 8080
+RATIO: 0.5
+DEBUG: true
+TOKEN: { host: CI_TOKEN }
+",
+        )
+        .unwrap();
+        assert_eq!(envs["NAME"], EnvValue::Value("srun".into()));
+        assert_eq!(envs["PORT"], EnvValue::Value("8080".into()));
+        assert_eq!(envs["RATIO"], EnvValue::Value("0.5".into()));
+        assert_eq!(envs["DEBUG"], EnvValue::Value("true".into()));
+        assert_eq!(
+            envs["TOKEN"],
+            EnvValue::Host {
+                host: "CI_TOKEN".into()
+            }
+        );
+        assert!(serde_yaml::from_str::<EnvValue>("[a]").is_err());
+    }
+
+    #[test]
+    fn keep_file_secrets_out_of_config() {
+        use crate::secret::{Secret, SecretSpec};
+
+        let specs: HashMap<String, SecretSpec> = serde_yaml::from_str(
+            "
+TOKEN: { provided: token }
+key.pem: { provided: key, as: file }
+",
+        )
+        .unwrap();
+        let provided = vec![
+            ("token".to_string(), Secret::new("s3cr3t-token")),
+            ("key".to_string(), Secret::new("s3cr3t-key")),
+        ]
+        .into_iter()
+        .collect();
+        let secrets = Secrets::resolve(specs, &provided, &mut Permissions::default()).unwrap();
+        let config = container_config(
+            "alpine".into(),
+            "/".into(),
+            vec!["NAME=srun".into()],
+            &secrets,
+            None,
+            true,
+            HostConfig::default(),
+        );
+        let envs = config.env.unwrap();
+        assert!(envs.contains(&"NAME=srun".to_string()));
+        assert!(envs.contains(&"TOKEN=s3cr3t-token".to_string()));
+        assert!(envs.iter().all(|env| !env.contains("s3cr3t-key")));
+        // a tmpfs to write the file secret into, even without other tmpfs
+        assert!(config.host_config.unwrap().tmpfs.unwrap()[SECRETS_DIR].contains("noexec"));
+        assert_eq!(
+            secrets.files()[0],
+            ("/run/secrets/key.pem".into(), "s3cr3t-key")
+        );
+
+        // the name of the file marking secrets ready is reserved
+        let specs = serde_yaml::from_str(".srun-ready: { provided: key, as: file }").unwrap();
+        assert!(Secrets::resolve(specs, &provided, &mut Permissions::default()).is_err());
+    }
+
+    #[test]
+    fn split_lines() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(b"TOKEN=se").is_empty());
+        assert_eq!(
+            buffer.push(b"cret\r\nnext\n\nla"),
+            vec![b"TOKEN=secret".to_vec(), b"next".to_vec(), vec![],]
+        );
+        assert_eq!(buffer.finish(), Some(b"la".to_vec()));
+
+        // long lines are passed on without splitting characters
+        let mut buffer = LineBuffer::default();
+        let mut chunk = vec![b'a'; MAX_LINE_LENGTH];
+        chunk.extend_from_slice(&"é".as_bytes()[..1]);
+        assert_eq!(buffer.push(&chunk), vec![vec![b'a'; MAX_LINE_LENGTH]]);
+        assert_eq!(buffer.push(&"é".as_bytes()[1..]), Vec::<Vec<u8>>::new());
+        assert_eq!(buffer.finish(), Some("é".as_bytes().to_vec()));
+        assert_eq!(LineBuffer::default().finish(), None);
+    }
+}
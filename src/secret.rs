@@ -0,0 +1,279 @@
+//! Secrets passed into sandbox containers, kept out of logs and results.
+
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Permissions};
+
+/// Directory where secrets are written as files, backed by a tmpfs.
+pub const SECRETS_DIR: &str = "/run/secrets";
+
+/// Empty file written under [`SECRETS_DIR`] once all file secrets are
+/// there, which the script waits for.
+const READY_FILE: &str = ".srun-ready";
+
+/// Shortest secret value masked in output. Masking shorter values would
+/// garble the output while barely hiding anything.
+pub const MIN_REDACTED_LENGTH: usize = 4;
+
+/// Secret value, which is never printed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// Get the actual value.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Parts of the value masked in output. Multi-line secrets might be
+    /// printed across lines, so each line is masked on its own.
+    fn redacted_parts(&self) -> impl Iterator<Item = &str> {
+        self.0
+            .lines()
+            .map(str::trim)
+            .filter(|v| v.chars().count() >= MIN_REDACTED_LENGTH)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+/// Where the value of a secret comes from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// Environment variable on the host, e.g. `{ env: CI_TOKEN }`.
+    Env(String),
+    /// File on the host, e.g. `{ file: ./token }`.
+    File(PathBuf),
+    /// Value provided to the runner by the library user, e.g.
+    /// `{ provided: token }`.
+    Provided(String),
+}
+
+/// How a secret is made available to the script.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretTarget {
+    /// Environment variable named after the secret.
+    #[default]
+    Env,
+    /// File named after the secret under `/run/secrets`.
+    File,
+}
+
+/// Secret specification in task.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretSpec {
+    #[serde(flatten)]
+    pub source: SecretSource,
+    #[serde(default, rename = "as")]
+    pub target: SecretTarget,
+}
+
+/// Resolved secrets to be injected into containers.
+#[derive(Clone, Debug, Default)]
+pub struct Secrets {
+    entries: Vec<(String, SecretTarget, Secret)>,
+}
+
+impl Secrets {
+    /// Get secret values from their sources, checking env and read
+    /// permissions for host sources.
+    pub(crate) fn resolve(
+        specs: HashMap<String, SecretSpec>,
+        provided: &HashMap<String, Secret>,
+        permissions: &mut Permissions,
+    ) -> Result<Self, Error> {
+        let mut entries = vec![];
+        for (name, spec) in specs {
+            // names are used as env names or file names in the script
+            let valid = !matches!(name.as_str(), "" | "." | ".." | READY_FILE)
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+            if !valid {
+                return Err(Error::SpecError(format!("invalid secret name: {}", name)));
+            }
+            let value = match &spec.source {
+                SecretSource::Env(var) => {
+                    permissions.forward_env(var)?;
+                    std::env::var(var).map(Secret).map_err(|_| {
+                        Error::SpecError(format!("environment variable {} not set on host", var))
+                    })?
+                }
+                SecretSource::File(path) => {
+                    let path = permissions.read_secret(&name, path)?;
+                    Secret(std::fs::read_to_string(path)?)
+                }
+                SecretSource::Provided(key) => provided
+                    .get(key)
+                    .cloned()
+                    .ok_or_else(|| Error::SpecError(format!("secret {} is not provided", key)))?,
+            };
+            let short = value
+                .0
+                .lines()
+                .map(str::trim)
+                .any(|v| !v.is_empty() && v.chars().count() < MIN_REDACTED_LENGTH);
+            if short || value.redacted_parts().next().is_none() {
+                log::warn!(
+                    "secret {} is shorter than {} characters and will not be masked in output",
+                    name,
+                    MIN_REDACTED_LENGTH
+                );
+            }
+            entries.push((name, spec.target, value));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn has_files(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|(_, t, _)| *t == SecretTarget::File)
+    }
+
+    /// Container environment variables of env secrets. File secrets are
+    /// not passed through the environment, which is kept in the container
+    /// config.
+    pub(crate) fn envs(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, target, _)| *target == SecretTarget::Env)
+            .map(|(name, _, value)| format!("{}={}", name, value.0))
+            .collect()
+    }
+
+    /// Paths and contents of files to be written into the started
+    /// container, ending with the file marking that all secrets are written.
+    pub(crate) fn files(&self) -> Vec<(String, &str)> {
+        let mut files = self
+            .entries
+            .iter()
+            .filter(|(_, target, _)| *target == SecretTarget::File)
+            .map(|(name, _, value)| (format!("{}/{}", SECRETS_DIR, name), value.expose()))
+            .collect::<Vec<_>>();
+        if !files.is_empty() {
+            files.push((format!("{}/{}", SECRETS_DIR, READY_FILE), ""));
+        }
+        files
+    }
+
+    /// Script lines waiting for file secrets before running the stage script.
+    pub(crate) fn prelude(&self) -> Vec<String> {
+        if !self.has_files() {
+            return vec![];
+        }
+        vec![format!(
+            "while [ ! -e '{}/{}' ]; do sleep 0.1; done",
+            SECRETS_DIR, READY_FILE
+        )]
+    }
+
+    /// Mask all secret values in `line` with `***`, except for those shorter
+    /// than [`MIN_REDACTED_LENGTH`].
+    pub fn redact(&self, line: &str) -> String {
+        let mut values = self
+            .entries
+            .iter()
+            .flat_map(|(_, _, value)| value.redacted_parts())
+            .collect::<Vec<_>>();
+        values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        let mut line = line.to_string();
+        for value in values {
+            if line.contains(value) {
+                line = line.replace(value, "***");
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make(entries: &[(&str, SecretTarget, &str)]) -> Secrets {
+        Secrets {
+            entries: entries
+                .iter()
+                .map(|(name, target, value)| (name.to_string(), *target, Secret::new(*value)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let secrets = make(&[
+            ("TOKEN", SecretTarget::Env, "s3cr3t"),
+            ("LONG", SecretTarget::Env, "s3cr3t-and-more"),
+            (
+                "KEY",
+                SecretTarget::File,
+                "-----BEGIN-----\n  abcdef  \n-----END-----\n",
+            ),
+            ("PIN", SecretTarget::Env, "1"),
+        ]);
+        assert_eq!(secrets.redact("token: s3cr3t!"), "token: ***!");
+        // longer values are masked first
+        assert_eq!(secrets.redact("s3cr3t-and-more"), "***");
+        assert_eq!(secrets.redact("key: abcdef"), "key: ***");
+        assert_eq!(secrets.redact("-----END-----"), "***");
+        // too short to be masked
+        assert_eq!(secrets.redact("exit 1"), "exit 1");
+        assert_eq!(Secrets::default().redact("s3cr3t"), "s3cr3t");
+    }
+
+    #[test]
+    fn mask_debug() {
+        let secret = Secret::new("s3cr3t");
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        let debug = format!("{:?}", make(&[("TOKEN", SecretTarget::Env, "s3cr3t")]));
+        assert!(!debug.contains("s3cr3t"));
+        assert_eq!(secret.expose(), "s3cr3t");
+    }
+
+    #[test]
+    fn write_file_secrets() {
+        let secrets = make(&[
+            ("TOKEN", SecretTarget::Env, "s3cr3t"),
+            ("key.pem", SecretTarget::File, "private"),
+        ]);
+        assert!(secrets.has_files());
+        assert_eq!(secrets.envs(), vec!["TOKEN=s3cr3t"]);
+        assert_eq!(
+            secrets.files(),
+            vec![
+                ("/run/secrets/key.pem".to_string(), "private"),
+                ("/run/secrets/.srun-ready".to_string(), ""),
+            ]
+        );
+        assert_eq!(
+            secrets.prelude(),
+            vec!["while [ ! -e '/run/secrets/.srun-ready' ]; do sleep 0.1; done"]
+        );
+        let secrets = make(&[("TOKEN", SecretTarget::Env, "s3cr3t")]);
+        assert!(secrets.prelude().is_empty());
+        assert!(secrets.files().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+//! Hardening options for sandbox containers.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use bollard::models::HostConfig;
+
+use crate::Error;
+
+/// Security profile applied to every container created by the sandbox.
+///
+/// The default profile is strict and intended for running untrusted code: all
+/// capabilities are dropped, the root filesystem is read-only (with a tmpfs
+/// mounted at `/tmp`), privilege escalation is disabled, processes run as
+/// `nobody`, and the number of processes is limited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityProfile {
+    /// Drop all capabilities, except the ones listed in `cap_add`.
+    pub cap_drop_all: bool,
+    /// Capabilities to keep (or add), e.g. `CHOWN`.
+    pub cap_add: Vec<String>,
+    /// Mount the container's root filesystem as read-only.
+    pub read_only: bool,
+    /// Tmpfs mounts, as container path to mount options.
+    pub tmpfs: HashMap<String, String>,
+    /// Set `no-new-privileges` to prevent privilege escalation.
+    pub no_new_privileges: bool,
+    /// User (and group) to run the script as, e.g. `65534:65534`.
+    pub user: Option<String>,
+    /// Path to a custom seccomp profile (JSON) on the host.
+    pub seccomp_profile: Option<PathBuf>,
+    /// Maximum number of processes inside the container.
+    pub pids_limit: Option<i64>,
+}
+
+impl Default for SecurityProfile {
+    fn default() -> Self {
+        Self {
+            cap_drop_all: true,
+            cap_add: vec![],
+            read_only: true,
+            tmpfs: vec![("/tmp".into(), "rw,nosuid,nodev,size=64m".into())]
+                .into_iter()
+                .collect(),
+            no_new_privileges: true,
+            user: Some("65534:65534".into()),
+            seccomp_profile: None,
+            pids_limit: Some(256),
+        }
+    }
+}
+
+impl SecurityProfile {
+    /// Profile that leaves everything to docker defaults.
+    pub fn permissive() -> Self {
+        Self {
+            cap_drop_all: false,
+            cap_add: vec![],
+            read_only: false,
+            tmpfs: HashMap::new(),
+            no_new_privileges: false,
+            user: None,
+            seccomp_profile: None,
+            pids_limit: None,
+        }
+    }
+
+    pub(crate) fn cap_drop(&self) -> Option<Vec<String>> {
+        if self.cap_drop_all {
+            Some(vec!["ALL".into()])
+        } else {
+            None
+        }
+    }
+
+    /// Build docker `SecurityOpt` list. Docker API expects the content of the
+    /// seccomp profile rather than its path, so the file is read here.
+    pub(crate) fn security_opt(&self) -> Result<Vec<String>, Error> {
+        let mut opts = vec![];
+        if self.no_new_privileges {
+            opts.push("no-new-privileges".into());
+        }
+        if let Some(path) = &self.seccomp_profile {
+            let profile = std::fs::read_to_string(path)?;
+            opts.push(format!("seccomp={}", profile));
+        }
+        Ok(opts)
+    }
+
+    /// Apply hardening options to the host config of a container.
+    pub(crate) fn apply(&self, config: &mut HostConfig) -> Result<(), Error> {
+        config.cap_drop = self.cap_drop();
+        config.cap_add = Some(self.cap_add.clone());
+        config.readonly_rootfs = Some(self.read_only);
+        config.tmpfs = Some(self.tmpfs.clone());
+        config.security_opt = Some(self.security_opt()?);
+        config.pids_limit = self.pids_limit;
+        Ok(())
+    }
+}
+
+/// Resource limits for sandbox containers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceLimits {
+    /// Number of CPUs, can be fractional.
+    pub cpus: f64,
+    /// Memory limit in bytes.
+    pub memory: u64,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            cpus: 1.0,
+            memory: 1 << 30,
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Apply limits to the host config of a container.
+    pub(crate) fn apply(&self, config: &mut HostConfig) {
+        config.nano_cpus = Some((self.cpus * 1e9) as i64);
+        config.memory = Some(self.memory as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_default_profile() {
+        let mut config = HostConfig::default();
+        SecurityProfile::default().apply(&mut config).unwrap();
+        assert_eq!(config.cap_drop, Some(vec!["ALL".to_string()]));
+        assert_eq!(config.cap_add, Some(vec![]));
+        assert_eq!(config.readonly_rootfs, Some(true));
+        assert_eq!(
+            config.security_opt,
+            Some(vec!["no-new-privileges".to_string()])
+        );
+        assert_eq!(config.pids_limit, Some(256));
+        assert!(config.tmpfs.unwrap().contains_key("/tmp"));
+    }
+
+    #[test]
+    fn apply_permissive_profile() {
+        let mut config = HostConfig::default();
+        SecurityProfile::permissive().apply(&mut config).unwrap();
+        assert_eq!(config.cap_drop, None);
+        assert_eq!(config.readonly_rootfs, Some(false));
+        assert_eq!(config.security_opt, Some(vec![]));
+        assert_eq!(config.pids_limit, None);
+        assert_eq!(config.tmpfs, Some(HashMap::new()));
+    }
+
+    #[test]
+    fn apply_seccomp_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seccomp.json");
+        std::fs::write(&path, "{}").unwrap();
+        let profile = SecurityProfile {
+            no_new_privileges: false,
+            seccomp_profile: Some(path),
+            cap_add: vec!["CHOWN".into()],
+            ..SecurityProfile::permissive()
+        };
+        let mut config = HostConfig::default();
+        profile.apply(&mut config).unwrap();
+        assert_eq!(config.security_opt, Some(vec!["seccomp={}".to_string()]));
+        assert_eq!(config.cap_add, Some(vec!["CHOWN".to_string()]));
+
+        let profile = SecurityProfile {
+            seccomp_profile: Some(dir.path().join("missing.json")),
+            ..SecurityProfile::default()
+        };
+        assert!(profile.apply(&mut HostConfig::default()).is_err());
+    }
+
+    #[test]
+    fn apply_resource_limits() {
+        let mut config = HostConfig::default();
+        ResourceLimits {
+            cpus: 0.5,
+            memory: 512 << 20,
+        }
+        .apply(&mut config);
+        assert_eq!(config.nano_cpus, Some(500_000_000));
+        assert_eq!(config.memory, Some(512 << 20));
+    }
+}
@@ -3,7 +3,12 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    runner::{Runner, RunnerReporter, StageSpec},
+    asset::AssetSpec,
+    permission::PermissionRequirements,
+    reporter::AsyncReporter,
+    runner::{RunResult, Runner, StageSpec},
+    sandbox::{EnvValue, Mount},
+    secret::SecretSpec,
     Error,
 };
 
@@ -21,7 +26,46 @@ pub struct Stage {
     #[serde(skip_serializing_if = "Option::is_none")]
     script: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    envs: Option<HashMap<String, String>>,
+    envs: Option<HashMap<String, EnvValue>>,
+    /// Merged with task-level assets, only visible to this stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assets: Option<HashMap<String, AssetSpec>>,
+    /// Merged with task-level mounts, only visible to this stage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mounts: Option<Mounts>,
+}
+
+/// Mounts, either as a map from container path to host path, or as a list of
+/// mount specifications.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Mounts {
+    Map(HashMap<String, String>),
+    List(Vec<Mount>),
+}
+
+impl Mounts {
+    /// Task-level mounts overridden by stage-level ones with the same target.
+    fn merge(task: &[Mount], stage: Option<Mounts>) -> Vec<Mount> {
+        let stage = stage.map(Mounts::into_vec).unwrap_or_default();
+        let mut mounts = task
+            .iter()
+            .filter(|m| !stage.iter().any(|s| s.target == m.target))
+            .cloned()
+            .collect::<Vec<_>>();
+        mounts.extend(stage);
+        mounts
+    }
+
+    fn into_vec(self) -> Vec<Mount> {
+        match self {
+            Mounts::Map(map) => map
+                .iter()
+                .map(|(target, source)| Mount::bind(source, target))
+                .collect(),
+            Mounts::List(list) => list,
+        }
+    }
 }
 
 /// Task specification.
@@ -30,9 +74,10 @@ pub struct Task {
     #[serde(skip_serializing_if = "Option::is_none")]
     stages: Option<Vec<Stage>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    assets: Option<HashMap<String, String>>,
+    permissions: Option<PermissionRequirements>,
+    /// Secrets available to all stages.
     #[serde(skip_serializing_if = "Option::is_none")]
-    mounts: Option<HashMap<String, String>>,
+    secrets: Option<HashMap<String, SecretSpec>>,
     #[serde(flatten)]
     defaults: Stage,
 }
@@ -44,13 +89,28 @@ impl Task {
         Ok(task)
     }
 
-    pub async fn run(self, runner: &mut Runner<'_, impl RunnerReporter>) -> Result<(), Error> {
+    pub async fn run(
+        mut self,
+        runner: &mut Runner<'_, impl AsyncReporter>,
+    ) -> Result<RunResult, Error> {
+        runner
+            .check_permissions(&self.permissions.unwrap_or_default())
+            .await?;
+        runner
+            .prepare_secrets(self.secrets.take().unwrap_or_default())
+            .await?;
+
         // TODO: prepare assets properly
         runner
-            .prepare_assets(self.assets.unwrap_or_default())
+            .prepare_assets(self.defaults.assets.clone().unwrap_or_default())
             .await?;
 
-        let mounts = self.mounts.unwrap_or_default();
+        let mounts = self
+            .defaults
+            .mounts
+            .take()
+            .map(Mounts::into_vec)
+            .unwrap_or_default();
         let stages = self.stages.unwrap_or_else(|| vec![Stage::default()]);
         for (i, stage) in stages.into_iter().enumerate() {
             let name = stage.name.unwrap_or_else(|| format!("stage-{}", i));
@@ -79,11 +139,63 @@ impl Task {
                             .envs
                             .or_else(|| defaults.envs.clone())
                             .unwrap_or_default(),
-                        mounts: mounts.to_owned(),
+                        mounts: Mounts::merge(&mounts, stage.mounts),
+                        // task-level assets are already prepared
+                        assets: stage.assets.unwrap_or_default(),
                     },
                 )
                 .await?;
         }
-        Ok(())
+        runner.finish().await?;
+        Ok(runner.result())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::{MountMode, MountType};
+
+    #[test]
+    fn parse_mounts() {
+        let mounts: Mounts = serde_yaml::from_str("{ /data: ./data }").unwrap();
+        assert_eq!(mounts.into_vec(), vec![Mount::bind("./data", "/data")]);
+
+        let mounts: Mounts = serde_yaml::from_str(
+            "
+- { source: ./data, target: /data, mode: ro }
+- { source: cache, target: /cache, type: volume }
+- { target: /scratch, type: tmpfs, size: 64m }
+",
+        )
+        .unwrap();
+        let mounts = mounts.into_vec();
+        assert_eq!(mounts[0].mode, Some(MountMode::Ro));
+        assert_eq!(mounts[0].kind, MountType::Bind);
+        assert_eq!(mounts[1].kind, MountType::Volume);
+        assert_eq!(mounts[1].source.as_deref(), Some("cache"));
+        assert_eq!(mounts[2].kind, MountType::Tmpfs);
+        assert_eq!(mounts[2].source, None);
+        assert_eq!(mounts[2].size.map(|s| s.0), Some(64 << 20));
+
+        assert!(serde_yaml::from_str::<Mounts>("[{ target: /a, typo: b }]").is_err());
+    }
+
+    #[test]
+    fn merge_mounts() {
+        let task = vec![Mount::bind("./a", "/a"), Mount::bind("./b", "/b")];
+        let stage: Mounts = serde_yaml::from_str("{ /b: ./stage-b, /c: ./c }").unwrap();
+        let mut merged = Mounts::merge(&task, Some(stage));
+        merged.sort_by(|a, b| a.target.cmp(&b.target));
+        assert_eq!(
+            merged,
+            vec![
+                Mount::bind("./a", "/a"),
+                Mount::bind("./stage-b", "/b"),
+                Mount::bind("./c", "/c"),
+            ]
+        );
+
+        assert_eq!(Mounts::merge(&task, None), task);
     }
 }